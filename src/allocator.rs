@@ -0,0 +1,337 @@
+use std::collections::BTreeMap;
+
+/// Every block starts on, and is sized to, a multiple of this many bytes.
+pub const BLOCK_ALIGN: usize = 4;
+/// Address 0 is never handed out, so programs and the collector can use it
+/// as a null pointer; the first block starts at this address instead.
+pub const FIRST_BLOCK: usize = BLOCK_ALIGN;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Block {
+    pub size: usize,
+    pub free: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AllocError {
+    /// The request would grow the heap past its limit.
+    OutOfMemory,
+    /// The address is not the start of a live block.
+    InvalidAddress,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct HeapStats {
+    pub heap_size: usize,
+    pub bytes_in_use: usize,
+    pub bytes_free: usize,
+    pub live_blocks: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+    /// `1 - largest_free_block / bytes_free`: 0.0 when all free space is
+    /// contiguous, approaching 1.0 as it is split into many small holes.
+    pub fragmentation: f64,
+}
+
+/// First-fit free-list allocator over `VM::heap`.
+///
+/// Block metadata is kept out of band, so the heap holds nothing but
+/// program data and addresses handed out are plain byte offsets into it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Allocator {
+    blocks: BTreeMap<usize, Block>,
+}
+
+fn align(offset: usize) -> usize {
+    offset.div_ceil(BLOCK_ALIGN) * BLOCK_ALIGN
+}
+
+/// Rounds a request up to a whole, non-empty number of aligned words.
+fn block_size(size: usize) -> usize {
+    align(size.max(1))
+}
+
+impl Allocator {
+    pub fn new() -> Self {
+        Allocator {
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Rebuilds an allocator from the blocks listed by `blocks()`.
+    pub fn from_blocks<I>(blocks: I) -> Self
+    where
+        I: IntoIterator<Item = (usize, Block)>,
+    {
+        Allocator {
+            blocks: blocks.into_iter().collect(),
+        }
+    }
+
+    pub fn block(&self, address: usize) -> Option<Block> {
+        self.blocks.get(&address).copied()
+    }
+
+    /// Finds the live block that `address` points into, if any.
+    pub fn live_block_containing(&self, address: usize) -> Option<(usize, Block)> {
+        self.blocks
+            .range(..=address)
+            .next_back()
+            .filter(|(start, block)| !block.free && address < *start + block.size)
+            .map(|(start, block)| (*start, *block))
+    }
+
+    /// Iterates over all blocks, live and free, in address order.
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, Block)> + '_ {
+        self.blocks.iter().map(|(address, block)| (*address, *block))
+    }
+
+    /// Allocates a zeroed block of at least `size` bytes, growing `heap` if
+    /// no free block is large enough.
+    pub fn allocate(&mut self, heap: &mut Vec<u8>, size: usize, limit: usize) -> Result<usize, AllocError> {
+        let size = block_size(size);
+        let fit = self
+            .blocks
+            .iter()
+            .find(|(_, block)| block.free && block.size >= size)
+            .map(|(address, _)| *address);
+        let address = match fit {
+            Some(address) => {
+                self.split(address, size);
+                address
+            }
+            None => {
+                let address = align(heap.len()).max(FIRST_BLOCK);
+                if address + size > limit {
+                    return Err(AllocError::OutOfMemory);
+                }
+                heap.resize(address + size, 0);
+                address
+            }
+        };
+        self.blocks.insert(address, Block { size, free: false });
+        heap[address..address + size].iter_mut().for_each(|byte| *byte = 0);
+        Ok(address)
+    }
+
+    /// Releases the block at `address` and returns its size.
+    pub fn free(&mut self, heap: &mut Vec<u8>, address: usize) -> Result<usize, AllocError> {
+        let size = match self.blocks.get_mut(&address) {
+            Some(block) if !block.free => {
+                block.free = true;
+                block.size
+            }
+            _ => return Err(AllocError::InvalidAddress),
+        };
+        self.coalesce(heap, address);
+        Ok(size)
+    }
+
+    /// Resizes the block at `address`, in place when possible, otherwise by
+    /// moving it. Returns the (possibly new) address of the block.
+    pub fn reallocate(
+        &mut self,
+        heap: &mut Vec<u8>,
+        address: usize,
+        size: usize,
+        limit: usize,
+    ) -> Result<usize, AllocError> {
+        let old = match self.blocks.get(&address) {
+            Some(block) if !block.free => *block,
+            _ => return Err(AllocError::InvalidAddress),
+        };
+        let size = block_size(size);
+        if size <= old.size {
+            if size < old.size {
+                self.split(address, size);
+                self.coalesce(heap, address + size);
+            }
+            return Ok(address);
+        }
+        let end = address + old.size;
+        if end == heap.len() {
+            if address + size > limit {
+                return Err(AllocError::OutOfMemory);
+            }
+            heap.resize(address + size, 0);
+            self.blocks.insert(address, Block { size, free: false });
+            return Ok(address);
+        }
+        if let Some(next) = self.blocks.get(&end).copied() {
+            if next.free && old.size + next.size >= size {
+                self.blocks.remove(&end);
+                self.blocks.insert(address, Block { size: old.size + next.size, free: false });
+                heap[end..end + next.size].iter_mut().for_each(|byte| *byte = 0);
+                self.split(address, size);
+                return Ok(address);
+            }
+        }
+        let new_address = self.allocate(heap, size, limit)?;
+        heap.copy_within(address..end, new_address);
+        self.free(heap, address)?;
+        Ok(new_address)
+    }
+
+    pub fn stats(&self, heap: &[u8]) -> HeapStats {
+        let mut stats = HeapStats {
+            heap_size: heap.len(),
+            bytes_in_use: 0,
+            bytes_free: 0,
+            live_blocks: 0,
+            free_blocks: 0,
+            largest_free_block: 0,
+            fragmentation: 0.0,
+        };
+        for block in self.blocks.values() {
+            if block.free {
+                stats.bytes_free += block.size;
+                stats.free_blocks += 1;
+                stats.largest_free_block = stats.largest_free_block.max(block.size);
+            } else {
+                stats.bytes_in_use += block.size;
+                stats.live_blocks += 1;
+            }
+        }
+        if stats.bytes_free > 0 {
+            stats.fragmentation = 1.0 - stats.largest_free_block as f64 / stats.bytes_free as f64;
+        }
+        stats
+    }
+
+    /// Splits the block at `address` so it is exactly `size` bytes, returning
+    /// the remainder to the free list.
+    fn split(&mut self, address: usize, size: usize) {
+        let block = self.blocks[&address];
+        if block.size > size {
+            self.blocks.insert(address, Block { size, ..block });
+            self.blocks.insert(address + size, Block { size: block.size - size, free: true });
+        }
+    }
+
+    /// Merges the free block at `address` with free neighbours and gives the
+    /// result back to the heap if it sits at the very end.
+    fn coalesce(&mut self, heap: &mut Vec<u8>, mut address: usize) {
+        let mut size = self.blocks[&address].size;
+        if let Some((prev, block)) = self.blocks.range(..address).next_back() {
+            if block.free && prev + block.size == address {
+                let prev = *prev;
+                size += block.size;
+                self.blocks.remove(&address);
+                address = prev;
+            }
+        }
+        if let Some(next) = self.blocks.get(&(address + size)).copied() {
+            if next.free {
+                self.blocks.remove(&(address + size));
+                size += next.size;
+            }
+        }
+        if address + size == heap.len() {
+            self.blocks.remove(&address);
+            heap.truncate(address);
+        } else {
+            self.blocks.insert(address, Block { size, free: true });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_bumps_and_aligns() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        assert_eq!(allocator.allocate(&mut heap, 3, 1024), Ok(FIRST_BLOCK));
+        assert_eq!(allocator.allocate(&mut heap, 8, 1024), Ok(FIRST_BLOCK + 4));
+        assert_eq!(heap.len(), FIRST_BLOCK + 12);
+        assert_eq!(allocator.allocate(&mut heap, 1024, 1024), Err(AllocError::OutOfMemory));
+    }
+
+    #[test]
+    fn test_free_reuses_and_splits() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.allocate(&mut heap, 16, 1024).unwrap();
+        let b = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        heap[a] = 9;
+        assert_eq!(allocator.free(&mut heap, a), Ok(16));
+        assert_eq!(allocator.free(&mut heap, a), Err(AllocError::InvalidAddress));
+        assert_eq!(allocator.allocate(&mut heap, 4, 1024), Ok(a));
+        assert_eq!(heap[a], 0);
+        assert_eq!(allocator.block(a + 4), Some(Block { size: 12, free: true }));
+        assert_eq!(heap.len(), b + 4);
+    }
+
+    #[test]
+    fn test_free_coalesces_and_shrinks_heap() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        let b = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        let c = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        allocator.free(&mut heap, a).unwrap();
+        allocator.free(&mut heap, b).unwrap();
+        assert_eq!(allocator.block(a), Some(Block { size: 8, free: true }));
+        allocator.free(&mut heap, c).unwrap();
+        assert_eq!(heap.len(), FIRST_BLOCK);
+        assert_eq!(allocator.blocks().count(), 0);
+    }
+
+    #[test]
+    fn test_reallocate() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        heap[a] = 7;
+        // The last block grows in place.
+        assert_eq!(allocator.reallocate(&mut heap, a, 8, 1024), Ok(a));
+        let b = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        allocator.allocate(&mut heap, 4, 1024).unwrap();
+        allocator.free(&mut heap, b).unwrap();
+        // A free neighbour is absorbed.
+        assert_eq!(allocator.reallocate(&mut heap, a, 12, 1024), Ok(a));
+        // A live neighbour forces a move.
+        let moved = allocator.reallocate(&mut heap, a, 32, 1024).unwrap();
+        assert_eq!(moved, a + 16);
+        assert_eq!(heap[moved], 7);
+        assert_eq!(allocator.block(a), Some(Block { size: 12, free: true }));
+        // Shrinking the last block hands the tail back to the heap.
+        assert_eq!(allocator.reallocate(&mut heap, moved, 8, 1024), Ok(moved));
+        assert_eq!(heap.len(), moved + 8);
+        assert_eq!(allocator.reallocate(&mut heap, 1, 4, 1024), Err(AllocError::InvalidAddress));
+    }
+
+    #[test]
+    fn test_live_block_containing() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.allocate(&mut heap, 8, 1024).unwrap();
+        let b = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        assert_eq!(allocator.live_block_containing(a + 7), Some((a, Block { size: 8, free: false })));
+        assert_eq!(allocator.live_block_containing(b + 4), None);
+        allocator.free(&mut heap, a).unwrap();
+        assert_eq!(allocator.live_block_containing(a), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let a = allocator.allocate(&mut heap, 8, 1024).unwrap();
+        allocator.allocate(&mut heap, 4, 1024).unwrap();
+        let c = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        allocator.allocate(&mut heap, 4, 1024).unwrap();
+        allocator.free(&mut heap, a).unwrap();
+        allocator.free(&mut heap, c).unwrap();
+        let stats = allocator.stats(&heap);
+        assert_eq!(stats.heap_size, FIRST_BLOCK + 20);
+        assert_eq!(stats.bytes_in_use, 8);
+        assert_eq!(stats.bytes_free, 12);
+        assert_eq!(stats.live_blocks, 2);
+        assert_eq!(stats.free_blocks, 2);
+        assert_eq!(stats.largest_free_block, 8);
+        assert!((stats.fragmentation - 1.0 / 3.0).abs() < 1e-9);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::asm::*;
 use crate::asm::parser::*;
+use crate::instructions::Opcode;
+use nom::{multispace, ErrorKind};
 use nom::types::CompleteStr;
 
 #[derive(Debug, PartialEq)]
@@ -15,21 +17,45 @@ pub struct AsmInstruction {
 impl AsmInstruction {
     fn extract_arg(token: &Token, bytes: &mut Vec<u8>) {
         match token {
-            Token::Register { num } => bytes.push(*num),
+            Token::Register { num } | Token::FloatRegister { num } => bytes.push(*num),
             Token::Integer { num } => {
                 let c = *num as u16;
                 bytes.push((c >> 8) as u8);
                 bytes.push(c as u8);
             }
+            Token::Float { num } => bytes.extend_from_slice(&num.to_be_bytes()),
             _ => panic!("Invalid argument type"),
         }
     }
+    /// Picks the register-immediate encoding when an arithmetic or
+    /// comparison instruction is given an integer as its second operand,
+    /// e.g. `add $0 #5` assembles to `ADDI` and adds 5 to `$0`.
+    fn encoding(&self, code: Opcode) -> Opcode {
+        match (code.immediate_form(), &self.arg2, &self.arg3) {
+            (Some(immediate), Some(Token::Integer { .. }), None) => immediate,
+            _ => code,
+        }
+    }
+    /// Register-immediate instructions take a register and a signed 16-bit
+    /// integer and nothing else; the parser rejects anything else rather
+    /// than truncating the constant.
+    fn immediate_is_valid(&self) -> bool {
+        let code = match self.opcode {
+            Some(Token::Opcode { code }) => code,
+            _ => return true,
+        };
+        let immediate = code.immediate_form().is_some() || code.register_form() != code;
+        match (&self.arg2, &self.arg3) {
+            (Some(Token::Integer { num }), arg3) if immediate => arg3.is_none() && i16::try_from(*num).is_ok(),
+            _ => true,
+        }
+    }
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
         if let Some(ref token) = self.opcode  {
             match token {
                 Token::Opcode { code } => {
-                    let b: u8 = (*code).into();
+                    let b: u8 = self.encoding(*code).into();
                     bytes.push(b);
                 },
                 _ => panic!("Invalid opcode"),
@@ -38,7 +64,7 @@ impl AsmInstruction {
         for arg in [&self.arg1, &self.arg2, &self.arg3].into_iter().flatten() {
             AsmInstruction::extract_arg(arg, &mut bytes);
         }
-        while bytes.len() < 4 {
+        while bytes.len() < 4 || bytes.len() % 4 != 0 {
             bytes.push(0);
         }
         bytes
@@ -52,13 +78,23 @@ named!(instruction<CompleteStr, AsmInstruction>,
         arg1: opt!(arg) >>
         arg2: opt!(arg) >>
         arg3: opt!(arg) >>
+        // Instructions without operands would otherwise leave the newline
+        // in front of the next one.
+        opt!(multispace) >>
         (AsmInstruction{opcode: Some(opcode), label, directive: None, arg1, arg2, arg3})
     )
 );
 
+/// Error code for a register-immediate instruction with a bad immediate.
+pub const INVALID_IMMEDIATE: u32 = 1;
+
 named!(pub  parse_instruction<CompleteStr, AsmInstruction>,
     do_parse!(
         instruction: alt!(instruction) >>
+        return_error!(
+            ErrorKind::Custom(INVALID_IMMEDIATE),
+            cond_reduce!(instruction.immediate_is_valid(), take!(0))
+        ) >>
         (instruction)
     )
 );
@@ -66,7 +102,6 @@ named!(pub  parse_instruction<CompleteStr, AsmInstruction>,
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::asm::Opcode;
 
     #[test]
     fn test_parse_instruction1() {
@@ -95,4 +130,39 @@ mod tests {
         };
         assert_eq!(parse_instruction(input), Ok((CompleteStr(""), expected)));
     }
+
+    #[test]
+    fn test_float_instruction_to_bytes() {
+        let (_, instruction) = parse_instruction(CompleteStr("fset $f2 #1.5")).unwrap();
+        assert_eq!(instruction.arg1, Some(Token::FloatRegister{num: 2}));
+        let mut expected = vec![34, 2];
+        expected.extend_from_slice(&1.5f64.to_be_bytes());
+        expected.extend_from_slice(&[0, 0]);
+        assert_eq!(instruction.to_bytes(), expected);
+        let (_, instruction) = parse_instruction(CompleteStr("fadd $f0 $f1 $f2")).unwrap();
+        assert_eq!(instruction.to_bytes(), vec![35, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_immediate_instruction_to_bytes() {
+        let (_, instruction) = parse_instruction(CompleteStr("add $3 #-2")).unwrap();
+        assert_eq!(instruction.to_bytes(), vec![56, 3, 255, 254]);
+        let (_, instruction) = parse_instruction(CompleteStr("gtq $1 #300")).unwrap();
+        assert_eq!(instruction.to_bytes(), vec![64, 1, 1, 44]);
+        let (_, instruction) = parse_instruction(CompleteStr("add $1 $2 $3")).unwrap();
+        assert_eq!(instruction.to_bytes(), vec![1, 1, 2, 3]);
+        let (_, instruction) = parse_instruction(CompleteStr("set $1 #300")).unwrap();
+        assert_eq!(instruction.to_bytes(), vec![0, 1, 1, 44]);
+    }
+
+    #[test]
+    fn test_invalid_immediates() {
+        for source in &["add $0 #70000", "sub $0 #-40000", "addi $0 #32768", "add $0 #5 $1"] {
+            assert!(parse_instruction(CompleteStr(source)).is_err(), "{}", source);
+        }
+        assert!(parse_instruction(CompleteStr("add $0 #-32768")).is_ok());
+        assert!(crate::asm::program_parser::parse_program(CompleteStr("inc $0
+add $0 #70000
+")).is_err());
+    }
 }
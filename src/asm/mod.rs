@@ -1,5 +1,13 @@
+// The baseline parsers predate these lints; they are kept as written.
+#[allow(
+    clippy::bool_assert_comparison,
+    clippy::redundant_field_names,
+    clippy::single_char_add_str,
+    clippy::unnecessary_to_owned
+)]
 mod parser;
 mod instruction_parser;
+#[allow(clippy::bool_assert_comparison)]
 pub mod program_parser;
 use crate::instructions::Opcode;
 
@@ -7,7 +15,9 @@ use crate::instructions::Opcode;
 pub enum Token {
     Opcode{code: Opcode},
     Register{num: u8},
+    FloatRegister{num: u8},
     Integer{num: i32},
+    Float{num: f64},
     Label{name: String},
     LabelUsage{name: String},
     Directive{name: String},
@@ -24,6 +24,16 @@ named!(pub register <CompleteStr, Token>,
     )
 );
 
+named!(pub float_register <CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("$f") >> // Floating point registers use $f
+            num: digit >>
+            (Token::FloatRegister{num: num.parse::<u8>().unwrap()})
+        )
+    )
+);
+
 named!(integer_arg<CompleteStr, Token>,
     ws!(
         do_parse!(
@@ -45,9 +55,35 @@ named!(integer_arg<CompleteStr, Token>,
     )
 );
 
+named!(float_arg<CompleteStr, Token>,
+    ws!(
+        do_parse!(
+            tag!("#") >>
+            sign: opt!(tag!("-")) >>
+            whole: digit >>
+            tag!(".") >>
+            fraction: digit >>
+            (
+                {
+                    let mut tmp = String::from("");
+                    if sign.is_some() {
+                        tmp.push('-');
+                    }
+                    tmp.push_str(&whole);
+                    tmp.push('.');
+                    tmp.push_str(&fraction);
+                    Token::Float{num: tmp.parse::<f64>().unwrap()}
+                }
+            )
+        )
+    )
+);
+
 named!(pub arg<CompleteStr, Token>,
     alt!(
+        float_arg |
         integer_arg |
+        float_register |
         register
     )
 );
@@ -145,6 +181,23 @@ mod tests {
         assert_eq!(result.is_err(), true);
     }
 
+    #[test]
+    fn test_parse_float_register() {
+        let result = float_register(CompleteStr("$f3"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::FloatRegister { num: 3 })));
+        assert!(float_register(CompleteStr("$3")).is_err());
+        assert_eq!(arg(CompleteStr("$3")), Ok((CompleteStr(""), Token::Register { num: 3 })));
+    }
+
+    #[test]
+    fn test_parse_float() {
+        let result = arg(CompleteStr("#-1.25"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Float { num: -1.25 })));
+        let result = arg(CompleteStr("#12"));
+        assert_eq!(result, Ok((CompleteStr(""), Token::Integer { num: 12 })));
+        assert!(float_arg(CompleteStr("#1.")).is_err());
+    }
+
     #[test]
     fn test_parse_label() {
         let result = label(CompleteStr("test:"));
@@ -1,4 +1,7 @@
+use std::collections::BTreeMap;
+
 use nom::types::CompleteStr;
+use crate::asm::Token;
 use crate::asm::instruction_parser::{AsmInstruction, parse_instruction};
 
 #[derive(Debug, PartialEq)]
@@ -14,6 +17,38 @@ impl AsmProgram {
         }
         bytes
     }
+
+    /// Records where each labelled instruction ends up in the bytecode.
+    pub fn debug_info(&self) -> DebugInfo {
+        let mut info = DebugInfo::default();
+        let mut offset = 0;
+        for instruction in &self.instructions {
+            if let Some(Token::Label { name }) = &instruction.label {
+                info.labels.insert(name.clone(), offset);
+            }
+            offset += instruction.to_bytes().len();
+        }
+        info
+    }
+}
+
+/// Maps label names to program addresses, for debuggers and other tools.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DebugInfo {
+    pub labels: BTreeMap<String, usize>,
+}
+
+impl DebugInfo {
+    pub fn address_of(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    pub fn label_at(&self, address: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, at)| **at == address)
+            .map(|(name, _)| name.as_str())
+    }
 }
 
 named!(pub parse_program<CompleteStr, AsmProgram>,
@@ -47,4 +82,15 @@ mod tests {
         assert_eq!(bytecode.len(), 4);
         println!("{:?}", bytecode);
     }
+
+    #[test]
+    fn test_debug_info() {
+        let (_, program) = parse_program(CompleteStr("set $0 #1\nfset $f0 #1.5\ninc start: $0\nhlt end:\n")).unwrap();
+        let info = program.debug_info();
+        assert_eq!(info.address_of("start"), Some(16));
+        assert_eq!(info.address_of("end"), Some(20));
+        assert_eq!(info.address_of("missing"), None);
+        assert_eq!(info.label_at(16), Some("start"));
+        assert_eq!(info.label_at(0), None);
+    }
 }
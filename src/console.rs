@@ -0,0 +1,171 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+/// Where `PRTI`, `PRTC` and `PRTS` write to. Defaults to stdout.
+pub struct Output(Box<dyn Write>);
+
+impl Output {
+    pub fn new<W: Write + 'static>(writer: W) -> Self {
+        Output(Box::new(writer))
+    }
+
+    pub fn stdout() -> Self {
+        Output::new(io::stdout())
+    }
+
+    /// Writes all of `bytes` and flushes, so output shows up as soon as the
+    /// instruction runs.
+    pub fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.0.write_all(bytes)?;
+        self.0.flush()
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::stdout()
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output")
+    }
+}
+
+/// Something `READI`, `READC` and `READLN` can read from.
+///
+/// Every `BufRead` is an input source, so tests can script input with an
+/// `io::Cursor`.
+pub trait InputSource {
+    /// The next byte, or `None` at end of input.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+
+    /// The next line without its line ending, or `None` at end of input.
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+impl<R: BufRead> InputSource for R {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.consume(1);
+        }
+        Ok(byte)
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = vec![];
+        if self.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+}
+
+/// Reads from stdin, locking it only for the duration of each read so the
+/// REPL can keep reading commands from it too.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdinInput;
+
+impl InputSource for StdinInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        io::stdin().lock().read_byte()
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        InputSource::read_line(&mut io::stdin().lock())
+    }
+}
+
+/// Where the read instructions take their input from. Defaults to stdin.
+pub struct Input(Box<dyn InputSource>);
+
+impl Input {
+    pub fn new<I: InputSource + 'static>(source: I) -> Self {
+        Input(Box::new(source))
+    }
+
+    pub fn stdin() -> Self {
+        Input::new(StdinInput)
+    }
+
+    pub fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        self.0.read_byte()
+    }
+
+    pub fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        self.0.read_line()
+    }
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Input::stdin()
+    }
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Input")
+    }
+}
+
+/// An in-memory sink that can be handed to a VM and read back afterwards.
+#[derive(Debug, Default, Clone)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub fn new() -> Self {
+        SharedBuffer::default()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+
+    /// The contents as text, with invalid UTF-8 replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted_input() {
+        let mut input = Input::new(io::Cursor::new("ab\r\nc\n\nlast"));
+        assert_eq!(input.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(input.read_line().unwrap(), Some(b"b".to_vec()));
+        assert_eq!(input.read_line().unwrap(), Some(b"c".to_vec()));
+        assert_eq!(input.read_line().unwrap(), Some(vec![]));
+        assert_eq!(input.read_line().unwrap(), Some(b"last".to_vec()));
+        assert_eq!(input.read_line().unwrap(), None);
+        assert_eq!(input.read_byte().unwrap(), None);
+    }
+}
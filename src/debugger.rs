@@ -0,0 +1,394 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::asm::program_parser::DebugInfo;
+use crate::instructions::Opcode;
+use crate::observer::DecodedInstruction;
+use crate::vm::{ExitReason, VmTrap, VM};
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A condition on a register, e.g. "`$3` is greater than 10".
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Condition {
+    pub register: usize,
+    pub comparison: Comparison,
+    pub value: i32,
+}
+
+impl Condition {
+    pub fn new(register: usize, comparison: Comparison, value: i32) -> Self {
+        Condition { register, comparison, value }
+    }
+
+    pub fn holds(&self, vm: &VM) -> bool {
+        let register = vm.registers[self.register];
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Breakpoint {
+    pub address: usize,
+    /// Only break when this holds; `None` breaks every time.
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+}
+
+/// Why a debugger command handed control back.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DebugEvent {
+    /// Stopped in front of the breakpoint at this address.
+    Breakpoint(usize),
+    /// A step, step-over or run-to-address finished where it was meant to.
+    Paused,
+    /// An observer paused the VM, typically a pausing watchpoint; its
+    /// `Watchpoints` handle has the details.
+    Watchpoint,
+    /// The VM halted, reached the end of the program or ran out of fuel.
+    Exited(ExitReason),
+    /// Reverse execution ran out of recorded history.
+    StartOfHistory,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum DebugError {
+    /// Labels can only be used once debug info has been attached.
+    NoDebugInfo,
+    UnknownLabel(String),
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::NoDebugInfo => write!(f, "no debug info loaded"),
+            DebugError::UnknownLabel(label) => write!(f, "unknown label {}", label),
+        }
+    }
+}
+
+impl std::error::Error for DebugError {}
+
+/// Breakpoints and stepping on top of a `VM`.
+///
+/// The debugger does not own the VM: every command takes it by `&mut`, so a
+/// front-end can inspect or modify registers and memory between commands.
+/// Breakpoints trigger before the instruction at their address runs, except
+/// for the one the VM is already stopped at when a command starts.
+#[derive(Debug, Default, Clone)]
+pub struct Debugger {
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    debug_info: Option<DebugInfo>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            debug_info: None,
+        }
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: DebugInfo) {
+        self.debug_info = Some(debug_info);
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.insert(address, None);
+    }
+
+    pub fn add_conditional_breakpoint(&mut self, address: usize, condition: Condition) {
+        self.insert(address, Some(condition));
+    }
+
+    /// Sets a breakpoint on a label and returns its address.
+    pub fn add_label_breakpoint(&mut self, label: &str, condition: Option<Condition>) -> Result<usize, DebugError> {
+        let address = self.resolve(label)?;
+        self.insert(address, condition);
+        Ok(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) -> Option<Breakpoint> {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Enables or disables the breakpoint at `address`, returning false if
+    /// there is none.
+    pub fn set_enabled(&mut self, address: usize, enabled: bool) -> bool {
+        match self.breakpoints.get_mut(&address) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.values()
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self, vm: &mut VM) -> Result<DebugEvent, VmTrap> {
+        match vm.step()? {
+            ExitReason::Stepped | ExitReason::Yielded => Ok(DebugEvent::Paused),
+            ExitReason::Paused => Ok(DebugEvent::Watchpoint),
+            reason => Ok(DebugEvent::Exited(reason)),
+        }
+    }
+
+    /// Like `step`, but runs a `CALL` through to its return.
+    pub fn step_over(&mut self, vm: &mut VM) -> Result<DebugEvent, VmTrap> {
+        let instruction = match DecodedInstruction::decode(&vm.program, vm.pcounter) {
+            Some(instruction) if instruction.opcode == Opcode::CALL => instruction,
+            _ => return self.step(vm),
+        };
+        let return_address = instruction.pc + 1 + instruction.opcode.operand_bytes();
+        let depth = vm.stack.len();
+        // Checking the stack depth stops a recursive call from pausing in an
+        // inner frame that happens to pass the same return address.
+        self.run_until(vm, |vm| vm.pcounter == return_address && vm.stack.len() == depth)
+    }
+
+    /// Runs until a breakpoint is hit or the VM stops.
+    pub fn resume(&mut self, vm: &mut VM) -> Result<DebugEvent, VmTrap> {
+        self.run_until(vm, |_| false)
+    }
+
+    /// Runs until the VM is about to execute the instruction at `address`.
+    /// Breakpoints on the way still stop it early.
+    pub fn run_to(&mut self, vm: &mut VM, address: usize) -> Result<DebugEvent, VmTrap> {
+        self.run_until(vm, |vm| vm.pcounter == address)
+    }
+
+    /// Undoes the last instruction. Needs `vm.history` to be recording.
+    pub fn reverse_step(&mut self, vm: &mut VM) -> DebugEvent {
+        if vm.step_back() {
+            DebugEvent::Paused
+        } else {
+            DebugEvent::StartOfHistory
+        }
+    }
+
+    /// Runs backwards until the VM is back in front of a breakpoint or the
+    /// recorded history is used up.
+    pub fn reverse_continue(&mut self, vm: &mut VM) -> DebugEvent {
+        while vm.step_back() {
+            if let Some(address) = self.breakpoint_hit(vm) {
+                return DebugEvent::Breakpoint(address);
+            }
+        }
+        DebugEvent::StartOfHistory
+    }
+
+    /// The breakpoint that should stop the VM at its current position.
+    pub fn breakpoint_hit(&self, vm: &VM) -> Option<usize> {
+        self.breakpoints
+            .get(&vm.pcounter)
+            .filter(|breakpoint| breakpoint.enabled)
+            .filter(|breakpoint| breakpoint.condition.is_none_or(|c| c.holds(vm)))
+            .map(|breakpoint| breakpoint.address)
+    }
+
+    fn run_until<F>(&mut self, vm: &mut VM, done: F) -> Result<DebugEvent, VmTrap>
+    where
+        F: Fn(&VM) -> bool,
+    {
+        loop {
+            match vm.step()? {
+                ExitReason::Stepped | ExitReason::Yielded => {}
+                ExitReason::Paused => return Ok(DebugEvent::Watchpoint),
+                reason => return Ok(DebugEvent::Exited(reason)),
+            }
+            if done(vm) {
+                return Ok(DebugEvent::Paused);
+            }
+            if let Some(address) = self.breakpoint_hit(vm) {
+                return Ok(DebugEvent::Breakpoint(address));
+            }
+        }
+    }
+
+    fn insert(&mut self, address: usize, condition: Option<Condition>) {
+        self.breakpoints.insert(
+            address,
+            Breakpoint {
+                address,
+                condition,
+                enabled: true,
+            },
+        );
+    }
+
+    /// Looks up the address of a label, e.g. for `run_to`.
+    pub fn resolve(&self, label: &str) -> Result<usize, DebugError> {
+        self.debug_info
+            .as_ref()
+            .ok_or(DebugError::NoDebugInfo)?
+            .address_of(label)
+            .ok_or_else(|| DebugError::UnknownLabel(label.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::program_parser::parse_program;
+    use crate::history::History;
+    use crate::watchpoint::{WatchAction, WatchOn, WatchTarget, Watchpoints};
+    use nom::types::CompleteStr;
+
+    /// Counts `$0` up to 3 in a loop, then calls a subroutine that sets `$5`.
+    fn test_program() -> (VM, DebugInfo) {
+        let source = "set $1 #3\n\
+                      set $2 #8\n\
+                      inc loop: $0\n\
+                      cmp $0 $1\n\
+                      jneq $2\n\
+                      set $3 #32\n\
+                      call $3\n\
+                      hlt\n\
+                      set sub: $5 #1\n\
+                      ret\n";
+        let (_, program) = parse_program(CompleteStr(source)).unwrap();
+        let mut vm = VM::new();
+        vm.program = program.to_bytes();
+        (vm, program.debug_info())
+    }
+
+    #[test]
+    fn test_breakpoint_and_resume() {
+        let (mut vm, _) = test_program();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(8);
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Breakpoint(8)));
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Breakpoint(8)));
+        assert_eq!(vm.registers[0], 1);
+        debugger.set_enabled(8, false);
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Exited(ExitReason::Halted)));
+        assert_eq!(vm.registers[0], 3);
+        assert_eq!(vm.registers[5], 1);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let (mut vm, _) = test_program();
+        let mut debugger = Debugger::new();
+        debugger.add_conditional_breakpoint(12, Condition::new(0, Comparison::Equal, 2));
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Breakpoint(12)));
+        assert_eq!(vm.registers[0], 2);
+    }
+
+    #[test]
+    fn test_label_breakpoint() {
+        let (mut vm, info) = test_program();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.add_label_breakpoint("sub", None), Err(DebugError::NoDebugInfo));
+        debugger.set_debug_info(info);
+        assert_eq!(
+            debugger.add_label_breakpoint("nope", None),
+            Err(DebugError::UnknownLabel("nope".to_string()))
+        );
+        assert_eq!(debugger.add_label_breakpoint("sub", None), Ok(32));
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Breakpoint(32)));
+        assert_eq!(vm.stack, vec![28]);
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let (mut vm, _) = test_program();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step(&mut vm), Ok(DebugEvent::Paused));
+        assert_eq!(vm.pcounter, 4);
+        assert_eq!(debugger.run_to(&mut vm, 24), Ok(DebugEvent::Paused));
+        assert_eq!(vm.registers[0], 3);
+        assert_eq!(debugger.step_over(&mut vm), Ok(DebugEvent::Paused));
+        assert_eq!(vm.pcounter, 28);
+        assert_eq!(vm.registers[5], 1);
+        assert!(vm.stack.is_empty());
+        assert_eq!(debugger.step_over(&mut vm), Ok(DebugEvent::Exited(ExitReason::Halted)));
+    }
+
+    #[test]
+    fn test_step_over_stops_at_breakpoint_inside_call() {
+        let (mut vm, _) = test_program();
+        let mut debugger = Debugger::new();
+        debugger.run_to(&mut vm, 24).unwrap();
+        debugger.add_breakpoint(36);
+        assert_eq!(debugger.step_over(&mut vm), Ok(DebugEvent::Breakpoint(36)));
+    }
+
+    #[test]
+    fn test_resume_stops_at_watchpoint() {
+        let (mut vm, _) = test_program();
+        let watchpoints = Watchpoints::new();
+        vm.observers.push(Box::new(watchpoints.clone()));
+        watchpoints.add(WatchTarget::Register(5), WatchOn::Write, WatchAction::Pause);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Watchpoint));
+        assert_eq!(vm.pcounter, 36);
+        assert_eq!(watchpoints.hits()[0].pc, 32);
+    }
+
+    #[test]
+    fn test_reverse_step_and_continue() {
+        let (mut vm, _) = test_program();
+        vm.history = Some(History::new());
+        let mut debugger = Debugger::new();
+        debugger.add_conditional_breakpoint(12, Condition::new(0, Comparison::Equal, 2));
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Breakpoint(12)));
+        debugger.clear_breakpoints();
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Exited(ExitReason::Halted)));
+        assert_eq!(vm.registers[5], 1);
+
+        // Back over HLT, RET and SET $5 into the subroutine.
+        for _ in 0..3 {
+            assert_eq!(debugger.reverse_step(&mut vm), DebugEvent::Paused);
+        }
+        assert_eq!(vm.pcounter, 32);
+        assert_eq!(vm.registers[5], 0);
+        assert_eq!(vm.stack, vec![28]);
+
+        debugger.add_conditional_breakpoint(12, Condition::new(0, Comparison::Equal, 1));
+        assert_eq!(debugger.reverse_continue(&mut vm), DebugEvent::Breakpoint(12));
+        assert_eq!(vm.registers[0], 1);
+        assert!(vm.stack.is_empty());
+        assert_eq!(debugger.reverse_continue(&mut vm), DebugEvent::StartOfHistory);
+        assert_eq!(vm.pcounter, 0);
+        assert_eq!(vm.registers[1], 0);
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Breakpoint(12)));
+    }
+
+    #[test]
+    fn test_resume_respects_fuel() {
+        let (mut vm, _) = test_program();
+        vm.fuel = Some(2);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.resume(&mut vm), Ok(DebugEvent::Exited(ExitReason::OutOfFuel)));
+        assert_eq!(vm.pcounter, 8);
+    }
+}
@@ -0,0 +1,306 @@
+//! Files reachable from bytecode through `FOPEN` and friends.
+//!
+//! Programs only see the directory tree below a root chosen by the host,
+//! addressed with relative paths. Without a root every path is outside
+//! the sandbox, so a VM has no file access unless the host grants it.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// `FOPEN` modes.
+pub const MODE_READ: i32 = 0;
+/// Creates the file or truncates it.
+pub const MODE_WRITE: i32 = 1;
+/// Creates the file and writes to its end.
+pub const MODE_APPEND: i32 = 2;
+/// Creates the file if needed without truncating it.
+pub const MODE_READ_WRITE: i32 = 3;
+
+/// `FSEEK` origins.
+pub const SEEK_START: i32 = 0;
+pub const SEEK_CURRENT: i32 = 1;
+pub const SEEK_END: i32 = 2;
+
+/// Descriptors are handed out from here; 0 to 2 are left alone so they
+/// are never mistaken for the standard streams.
+pub const FIRST_DESCRIPTOR: i32 = 3;
+/// Files are numbered below this and sockets from it, so a descriptor
+/// never names both a file and a socket.
+pub const FIRST_SOCKET_DESCRIPTOR: i32 = 1 << 16;
+
+#[derive(Debug)]
+pub enum FileError {
+    /// The path leads outside the root, or no root is configured.
+    Escape(String),
+    BadDescriptor(i32),
+    Io(io::Error),
+}
+
+impl From<io::Error> for FileError {
+    fn from(error: io::Error) -> Self {
+        FileError::Io(error)
+    }
+}
+
+/// The files a VM has open and the directory they must live under.
+#[derive(Debug)]
+pub struct FileTable {
+    root: Option<PathBuf>,
+    open: BTreeMap<i32, File>,
+    next_descriptor: i32,
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        FileTable::new()
+    }
+}
+
+impl FileTable {
+    /// A table with no root, which refuses every path.
+    pub fn new() -> Self {
+        FileTable {
+            root: None,
+            open: BTreeMap::new(),
+            next_descriptor: FIRST_DESCRIPTOR,
+        }
+    }
+
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Confines programs to `root`, which must be an existing directory.
+    /// Files that are already open stay open.
+    pub fn set_root<P: AsRef<Path>>(&mut self, root: P) -> io::Result<()> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "sandbox root is not a directory"));
+        }
+        self.root = Some(root);
+        Ok(())
+    }
+
+    /// Turns file access off again.
+    pub fn clear_root(&mut self) {
+        self.root = None;
+    }
+
+    /// Maps a program's path to one under the root. Absolute paths, `..`
+    /// past the root and symlinks pointing out of it are all escapes.
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, FileError> {
+        let escape = || FileError::Escape(path.to_string());
+        let root = self.root.as_ref().ok_or_else(escape)?;
+        let mut resolved = root.clone();
+        let mut depth = 0;
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(part) => {
+                    resolved.push(part);
+                    depth += 1;
+                }
+                Component::CurDir => {}
+                Component::ParentDir if depth > 0 => {
+                    resolved.pop();
+                    depth -= 1;
+                }
+                _ => return Err(escape()),
+            }
+        }
+        if depth == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "path does not name a file").into());
+        }
+        // A file that does not exist yet is checked through its directory.
+        // A dangling symlink does not resolve either, but opening it would
+        // create its target wherever that is.
+        let real = match resolved.canonicalize() {
+            Ok(real) => real,
+            Err(_) if resolved.symlink_metadata().is_ok() => return Err(escape()),
+            Err(_) => {
+                let parent = resolved.parent().expect("resolved path is below the root");
+                parent.canonicalize()?.join(resolved.file_name().expect("path names a file"))
+            }
+        };
+        if !real.starts_with(root) {
+            return Err(escape());
+        }
+        Ok(real)
+    }
+
+    /// The next descriptor not in use, wrapping around before the socket
+    /// range.
+    fn next_free_descriptor(&mut self) -> Result<i32, FileError> {
+        for _ in FIRST_DESCRIPTOR..FIRST_SOCKET_DESCRIPTOR {
+            let descriptor = self.next_descriptor;
+            self.next_descriptor = if descriptor + 1 < FIRST_SOCKET_DESCRIPTOR {
+                descriptor + 1
+            } else {
+                FIRST_DESCRIPTOR
+            };
+            if !self.open.contains_key(&descriptor) {
+                return Ok(descriptor);
+            }
+        }
+        Err(io::Error::other("too many open files").into())
+    }
+
+    /// Opens `path` in one of the `MODE_*` modes and returns its descriptor.
+    ///
+    /// The path is resolved again once the file is open, so a symlink
+    /// swapped in after `resolve` checked it is refused before anything is
+    /// read or truncated. Such a race can at worst leave an empty file
+    /// behind where the symlink pointed.
+    pub fn open(&mut self, path: &str, mode: i32) -> Result<i32, FileError> {
+        let mut options = OpenOptions::new();
+        match mode {
+            MODE_READ => options.read(true),
+            // Truncated only once the file is known to be in the sandbox.
+            MODE_WRITE => options.write(true).create(true).truncate(false),
+            MODE_APPEND => options.append(true).create(true),
+            MODE_READ_WRITE => options.read(true).write(true).create(true).truncate(false),
+            _ => {
+                let message = format!("unknown file mode {}", mode);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+            }
+        };
+        let resolved = self.resolve(path)?;
+        let descriptor = self.next_free_descriptor()?;
+        let file = options.open(&resolved)?;
+        let real = resolved.canonicalize()?;
+        let root = self.root.as_ref().ok_or_else(|| FileError::Escape(path.to_string()))?;
+        if !real.starts_with(root) || !same_file(&file, &real)? {
+            return Err(FileError::Escape(path.to_string()));
+        }
+        if mode == MODE_WRITE {
+            file.set_len(0)?;
+        }
+        self.open.insert(descriptor, file);
+        Ok(descriptor)
+    }
+
+    fn file(&mut self, descriptor: i32) -> Result<&mut File, FileError> {
+        self.open.get_mut(&descriptor).ok_or(FileError::BadDescriptor(descriptor))
+    }
+
+    /// Reads up to `buf.len()` bytes; 0 means end of file.
+    pub fn read(&mut self, descriptor: i32, buf: &mut [u8]) -> Result<usize, FileError> {
+        Ok(self.file(descriptor)?.read(buf)?)
+    }
+
+    pub fn write(&mut self, descriptor: i32, bytes: &[u8]) -> Result<(), FileError> {
+        Ok(self.file(descriptor)?.write_all(bytes)?)
+    }
+
+    /// Moves to `offset` from one of the `SEEK_*` origins and returns the
+    /// new position from the start of the file.
+    pub fn seek(&mut self, descriptor: i32, offset: i32, origin: i32) -> Result<u64, FileError> {
+        let position = match origin {
+            SEEK_START if offset >= 0 => SeekFrom::Start(offset as u64),
+            SEEK_CURRENT => SeekFrom::Current(offset as i64),
+            SEEK_END => SeekFrom::End(offset as i64),
+            _ => {
+                let message = format!("cannot seek to {} from origin {}", offset, origin);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+            }
+        };
+        Ok(self.file(descriptor)?.seek(position)?)
+    }
+
+    pub fn close(&mut self, descriptor: i32) -> Result<(), FileError> {
+        self.open.remove(&descriptor).map(drop).ok_or(FileError::BadDescriptor(descriptor))
+    }
+
+    /// Closes every open file, e.g. when a program is reset.
+    pub fn close_all(&mut self) {
+        self.open.clear();
+    }
+
+    pub fn descriptors(&self) -> impl Iterator<Item = i32> + '_ {
+        self.open.keys().copied()
+    }
+}
+
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (opened, named) = (file.metadata()?, path.metadata()?);
+    Ok(opened.dev() == named.dev() && opened.ino() == named.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(_file: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn sandbox(name: &str) -> (PathBuf, FileTable) {
+        let dir = std::env::temp_dir().join(format!("crabvm-files-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("inner")).unwrap();
+        let mut files = FileTable::new();
+        files.set_root(&dir).unwrap();
+        (dir, files)
+    }
+
+    #[test]
+    fn test_resolve_rejects_escapes() {
+        let (dir, files) = sandbox("resolve");
+        let root = dir.canonicalize().unwrap();
+        assert_eq!(files.resolve("inner/../a.txt").unwrap(), root.join("a.txt"));
+        assert_eq!(files.resolve("./inner/b").unwrap(), root.join("inner/b"));
+        for path in &["../a.txt", "inner/../../a.txt", "/etc/passwd"] {
+            assert!(matches!(files.resolve(path), Err(FileError::Escape(_))), "{}", path);
+        }
+        assert!(matches!(FileTable::new().resolve("a.txt"), Err(FileError::Escape(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_rejects_dangling_symlinks() {
+        let (dir, mut files) = sandbox("dangling");
+        let outside = std::env::temp_dir().join(format!("crabvm-outside-{}", std::process::id()));
+        std::os::unix::fs::symlink(&outside, dir.join("inner/link")).unwrap();
+        assert!(matches!(files.resolve("inner/link"), Err(FileError::Escape(_))));
+        assert!(matches!(files.open("inner/link", MODE_WRITE), Err(FileError::Escape(_))));
+        assert!(!outside.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_read_write_seek_close() {
+        let (dir, mut files) = sandbox("rw");
+        let fd = files.open("inner/data", MODE_WRITE).unwrap();
+        assert_eq!(fd, FIRST_DESCRIPTOR);
+        files.write(fd, b"hello").unwrap();
+        files.close(fd).unwrap();
+        assert!(matches!(files.close(fd), Err(FileError::BadDescriptor(3))));
+
+        let fd = files.open("inner/data", MODE_READ_WRITE).unwrap();
+        assert_eq!(files.seek(fd, -2, SEEK_END).unwrap(), 3);
+        let mut buf = [0; 4];
+        assert_eq!(files.read(fd, &mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(files.read(fd, &mut buf).unwrap(), 0);
+        assert_eq!(files.descriptors().collect::<Vec<_>>(), vec![fd]);
+        files.close_all();
+        assert!(matches!(files.open("missing", MODE_READ), Err(FileError::Io(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_descriptors_skip_open_files_when_wrapping() {
+        let (dir, mut files) = sandbox("wrap");
+        let first = files.open("a", MODE_WRITE).unwrap();
+        files.next_descriptor = FIRST_SOCKET_DESCRIPTOR - 1;
+        assert_eq!(files.open("b", MODE_WRITE).unwrap(), FIRST_SOCKET_DESCRIPTOR - 1);
+        assert_eq!(files.open("c", MODE_WRITE).unwrap(), first + 1);
+        assert_eq!(files.descriptors().count(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
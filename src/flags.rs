@@ -0,0 +1,148 @@
+/// The VM's status register.
+///
+/// Arithmetic instructions and `CMP` set all four bits from their result;
+/// the conditional branches are defined purely in terms of them, using the
+/// usual signed interpretation (`less` is `negative != overflow`). `FCMP`
+/// with a NaN operand sets `UNORDERED`, under which none of `equal`, `less`
+/// and `greater` hold.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const ZERO: u8 = 1;
+    pub const NEGATIVE: u8 = 1 << 1;
+    pub const CARRY: u8 = 1 << 2;
+    pub const OVERFLOW: u8 = 1 << 3;
+    pub const UNORDERED: u8 = 1 << 4;
+
+    pub fn from_bits(bits: u8) -> Self {
+        Flags(bits & (Flags::ZERO | Flags::NEGATIVE | Flags::CARRY | Flags::OVERFLOW | Flags::UNORDERED))
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Flags describing an integer `result` with the given carry and overflow.
+    pub fn from_result(result: i32, carry: bool, overflow: bool) -> Self {
+        let mut flags = Flags::default();
+        flags.set(Flags::ZERO, result == 0);
+        flags.set(Flags::NEGATIVE, result < 0);
+        flags.set(Flags::CARRY, carry);
+        flags.set(Flags::OVERFLOW, overflow);
+        flags
+    }
+
+    /// Flags for `CMP`: those of the wrapping subtraction `a - b`, with
+    /// carry set when it borrows.
+    pub fn compare(a: i32, b: i32) -> Self {
+        let (result, overflow) = a.overflowing_sub(b);
+        Flags::from_result(result, (a as u32) < (b as u32), overflow)
+    }
+
+    /// Flags for `FCMP`. An unordered comparison (either side NaN) sets
+    /// `UNORDERED` and the carry bit.
+    pub fn compare_float(a: f64, b: f64) -> Self {
+        let mut flags = Flags::default();
+        match a.partial_cmp(&b) {
+            Some(ordering) => {
+                flags.set(Flags::ZERO, ordering.is_eq());
+                flags.set(Flags::NEGATIVE, ordering.is_lt());
+            }
+            None => {
+                flags.set(Flags::UNORDERED, true);
+                flags.set(Flags::CARRY, true);
+            }
+        }
+        flags
+    }
+
+    pub fn set(&mut self, flag: u8, value: bool) {
+        if value {
+            self.0 |= flag;
+        } else {
+            self.0 &= !flag;
+        }
+    }
+
+    pub fn zero(self) -> bool {
+        self.0 & Flags::ZERO != 0
+    }
+
+    pub fn negative(self) -> bool {
+        self.0 & Flags::NEGATIVE != 0
+    }
+
+    pub fn carry(self) -> bool {
+        self.0 & Flags::CARRY != 0
+    }
+
+    pub fn overflow(self) -> bool {
+        self.0 & Flags::OVERFLOW != 0
+    }
+
+    pub fn equal(self) -> bool {
+        self.zero()
+    }
+
+    pub fn unordered(self) -> bool {
+        self.0 & Flags::UNORDERED != 0
+    }
+
+    pub fn less(self) -> bool {
+        !self.unordered() && self.negative() != self.overflow()
+    }
+
+    pub fn greater(self) -> bool {
+        !self.unordered() && !self.zero() && self.negative() == self.overflow()
+    }
+
+    pub fn less_or_equal(self) -> bool {
+        self.less() || self.equal()
+    }
+
+    pub fn greater_or_equal(self) -> bool {
+        self.greater() || self.equal()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        assert!(Flags::compare(3, 3).equal());
+        assert!(Flags::compare(-3, 3).less());
+        assert!(Flags::compare(3, -3).greater());
+        // The subtraction overflows but the ordering is still right.
+        assert!(Flags::compare(i32::MIN, 1).less());
+        assert!(Flags::compare(i32::MAX, -1).greater());
+        assert!(Flags::compare(1, -1).carry());
+        assert!(!Flags::compare(-1, 1).carry());
+    }
+
+    #[test]
+    fn test_compare_float() {
+        assert!(Flags::compare_float(1.5, 1.5).equal());
+        assert!(Flags::compare_float(-1.0, 1.5).less());
+        assert!(Flags::compare_float(2.0, 1.5).greater());
+        let unordered = Flags::compare_float(f64::NAN, 1.0);
+        assert!(unordered.carry());
+        assert!(!unordered.equal());
+        assert!(!unordered.less());
+        assert!(!unordered.greater());
+        assert!(!unordered.less_or_equal());
+        assert!(!unordered.greater_or_equal());
+    }
+
+    #[test]
+    fn test_set_and_bits() {
+        let mut flags = Flags::default();
+        flags.set(Flags::CARRY, true);
+        flags.set(Flags::ZERO, true);
+        flags.set(Flags::ZERO, false);
+        assert_eq!(flags.bits(), Flags::CARRY);
+        assert_eq!(Flags::from_bits(0xff).bits(), 0x1f);
+    }
+}
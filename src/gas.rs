@@ -0,0 +1,72 @@
+use crate::instructions::Opcode;
+
+/// Gas charged for every opcode unless the table says otherwise.
+pub const DEFAULT_OPCODE_COST: u64 = 1;
+/// Extra gas charged per byte requested by `ALOC` or added by `REALLOC`.
+pub const DEFAULT_HEAP_BYTE_COST: u64 = 1;
+
+/// How much gas each instruction costs when the VM is metered.
+///
+/// An instruction is charged its opcode's cost before it runs; `ALOC` is
+/// additionally charged `heap_byte_cost` for every byte it asks for, and
+/// `REALLOC` for every byte it grows a block by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GasTable {
+    costs: [u64; 256],
+    pub heap_byte_cost: u64,
+}
+
+impl Default for GasTable {
+    fn default() -> Self {
+        GasTable::new()
+    }
+}
+
+impl GasTable {
+    pub fn new() -> Self {
+        GasTable {
+            costs: [DEFAULT_OPCODE_COST; 256],
+            heap_byte_cost: DEFAULT_HEAP_BYTE_COST,
+        }
+    }
+
+    /// Builds a table from per-opcode-byte costs, as returned by `costs`.
+    pub fn from_costs(costs: [u64; 256], heap_byte_cost: u64) -> Self {
+        GasTable { costs, heap_byte_cost }
+    }
+
+    /// Costs indexed by opcode byte.
+    pub fn costs(&self) -> &[u64; 256] {
+        &self.costs
+    }
+
+    pub fn cost(&self, opcode: Opcode) -> u64 {
+        self.costs[u8::from(opcode) as usize]
+    }
+
+    pub fn set_cost(&mut self, opcode: Opcode, cost: u64) {
+        self.costs[u8::from(opcode) as usize] = cost;
+    }
+
+    /// Gas charged by `ALOC` or `REALLOC` for `bytes` on top of the opcode
+    /// cost.
+    pub fn heap_cost(&self, bytes: usize) -> u64 {
+        self.heap_byte_cost.saturating_mul(bytes as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_costs() {
+        let mut table = GasTable::new();
+        assert_eq!(table.cost(Opcode::ADD), DEFAULT_OPCODE_COST);
+        table.set_cost(Opcode::GC, 50);
+        assert_eq!(table.cost(Opcode::GC), 50);
+        assert_eq!(table.cost(Opcode::ADD), DEFAULT_OPCODE_COST);
+        table.heap_byte_cost = 2;
+        assert_eq!(table.heap_cost(10), 20);
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use crate::allocator::Allocator;
+
+/// Bytes allocated between automatic collections when none is configured.
+pub const DEFAULT_GC_THRESHOLD: usize = 64 * 1024;
+
+/// Outcome of a single collection.
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct GcReport {
+    pub live_blocks: usize,
+    pub freed_blocks: usize,
+    pub bytes_freed: usize,
+    pub duration: Duration,
+}
+
+/// Totals across every collection since the VM was created.
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct GcStats {
+    pub collections: u64,
+    pub blocks_freed: u64,
+    pub bytes_freed: u64,
+    pub total_time: Duration,
+    pub last: Option<GcReport>,
+}
+
+/// Mark-and-sweep collector over the blocks handed out by the `Allocator`.
+///
+/// The allocator's block table doubles as the object headers: any register,
+/// stack slot or aligned heap word whose value points into a live block is
+/// treated as a reference to it. Collection is conservative, so an integer
+/// that happens to look like an address keeps its block alive.
+///
+/// `GC` always collects; `enabled` only controls automatic collections,
+/// which run before an allocation once `threshold` bytes have been
+/// allocated since the previous one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collector {
+    pub enabled: bool,
+    pub threshold: usize,
+    pub stats: GcStats,
+    allocated_since_collection: usize,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Collector::new()
+    }
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Collector {
+            enabled: false,
+            threshold: DEFAULT_GC_THRESHOLD,
+            stats: GcStats::default(),
+            allocated_since_collection: 0,
+        }
+    }
+
+    pub fn record_allocation(&mut self, bytes: usize) {
+        self.allocated_since_collection += bytes;
+    }
+
+    pub fn allocated_since_collection(&self) -> usize {
+        self.allocated_since_collection
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.enabled && self.allocated_since_collection >= self.threshold
+    }
+
+    pub fn collect<I>(&mut self, allocator: &mut Allocator, heap: &mut Vec<u8>, roots: I) -> GcReport
+    where
+        I: IntoIterator<Item = i32>,
+    {
+        let start = Instant::now();
+        let mut marked = BTreeSet::new();
+        let mut pending: Vec<i32> = roots.into_iter().collect();
+        while let Some(value) = pending.pop() {
+            if value < 0 {
+                continue;
+            }
+            let (address, block) = match allocator.live_block_containing(value as usize) {
+                Some(found) => found,
+                None => continue,
+            };
+            if !marked.insert(address) {
+                continue;
+            }
+            for word in heap[address..address + block.size].chunks_exact(4) {
+                pending.push(i32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+            }
+        }
+
+        let garbage: Vec<(usize, usize)> = allocator
+            .blocks()
+            .filter(|(address, block)| !block.free && !marked.contains(address))
+            .map(|(address, block)| (address, block.size))
+            .collect();
+        for (address, _) in &garbage {
+            allocator
+                .free(heap, *address)
+                .expect("collector only frees live blocks");
+        }
+
+        let report = GcReport {
+            live_blocks: marked.len(),
+            freed_blocks: garbage.len(),
+            bytes_freed: garbage.iter().map(|(_, size)| size).sum(),
+            duration: start.elapsed(),
+        };
+        self.allocated_since_collection = 0;
+        self.stats.collections += 1;
+        self.stats.blocks_freed += report.freed_blocks as u64;
+        self.stats.bytes_freed += report.bytes_freed as u64;
+        self.stats.total_time += report.duration;
+        self.stats.last = Some(report);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_unreachable() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let mut collector = Collector::new();
+        let a = allocator.allocate(&mut heap, 8, 1024).unwrap();
+        let b = allocator.allocate(&mut heap, 8, 1024).unwrap();
+        allocator.allocate(&mut heap, 8, 1024).unwrap();
+        let report = collector.collect(&mut allocator, &mut heap, vec![b as i32 + 4, -1]);
+        assert_eq!(report.live_blocks, 1);
+        assert_eq!(report.freed_blocks, 2);
+        assert_eq!(report.bytes_freed, 16);
+        assert!(allocator.block(a).unwrap().free);
+        assert!(!allocator.block(b).unwrap().free);
+        assert_eq!(heap.len(), b + 8);
+        assert_eq!(collector.stats.collections, 1);
+        assert_eq!(collector.stats.last, Some(report));
+    }
+
+    #[test]
+    fn test_collect_follows_heap_pointers() {
+        let mut heap = vec![];
+        let mut allocator = Allocator::new();
+        let mut collector = Collector::new();
+        allocator.allocate(&mut heap, 4, 1024).unwrap();
+        let a = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        let b = allocator.allocate(&mut heap, 4, 1024).unwrap();
+        heap[a..a + 4].copy_from_slice(&(b as i32).to_be_bytes());
+        let report = collector.collect(&mut allocator, &mut heap, vec![a as i32]);
+        assert_eq!(report.live_blocks, 2);
+        assert_eq!(report.freed_blocks, 1);
+        assert!(!allocator.block(b).unwrap().free);
+    }
+
+    #[test]
+    fn test_should_collect() {
+        let mut collector = Collector::new();
+        collector.threshold = 16;
+        collector.record_allocation(16);
+        assert!(!collector.should_collect());
+        collector.enabled = true;
+        assert!(collector.should_collect());
+        collector.collect(&mut Allocator::new(), &mut vec![], vec![]);
+        assert!(!collector.should_collect());
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+use crate::flags::Flags;
+use crate::instructions::Opcode;
+use crate::snapshot::Snapshot;
+
+/// Instructions recorded between two periodic checkpoints.
+pub const DEFAULT_CHECKPOINT_INTERVAL: usize = 1024;
+/// Rough upper bound on the memory a recording may use, in bytes.
+pub const DEFAULT_HISTORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// What one instruction changed, with the old values needed to undo it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    pub pcounter: usize,
+    pub flags: Flags,
+    pub remainder: u32,
+    pub gas: Option<u64>,
+    pub registers: Vec<(usize, i32)>,
+    pub float_registers: Vec<(usize, f64)>,
+    pub heap: Vec<(usize, Vec<u8>)>,
+    pub stack_len: usize,
+    /// Values popped off the stack, in the order they were popped.
+    pub popped: Vec<i32>,
+}
+
+impl Delta {
+    fn size(&self) -> usize {
+        size_of::<Delta>()
+            + self.registers.len() * size_of::<(usize, i32)>()
+            + self.float_registers.len() * size_of::<(usize, f64)>()
+            + self.heap.iter().map(|(_, bytes)| size_of::<(usize, Vec<u8>)>() + bytes.len()).sum::<usize>()
+            + self.popped.len() * size_of::<i32>()
+    }
+}
+
+/// A full snapshot followed by the instructions executed after it.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    checkpoint: Snapshot,
+    /// `None` stands for an instruction undone by restoring the
+    /// checkpoint; it can only be the first one in a segment.
+    steps: Vec<Option<Delta>>,
+    bytes: usize,
+}
+
+/// An undo log for reverse execution, filled in by the VM while
+/// `VM::history` is set.
+///
+/// Most instructions are recorded as a `Delta` of the registers, flags,
+/// heap bytes and stack slots they overwrite. Instructions that reshape
+/// the heap (allocation and garbage collection) or hand it to the host
+/// (`SYSCALL`), as well as `SPAWN` and `EXIT`, instead start a new
+/// segment with a full snapshot, as does every `checkpoint_interval`th
+/// instruction. Once the recording grows past `memory_limit` the oldest
+/// segments are dropped, so only the most recent history can be stepped
+/// back through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    pub checkpoint_interval: usize,
+    pub memory_limit: usize,
+    segments: VecDeque<Segment>,
+    bytes: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new()
+    }
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            checkpoint_interval: DEFAULT_CHECKPOINT_INTERVAL,
+            memory_limit: DEFAULT_HISTORY_LIMIT,
+            segments: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Instructions that are restored from a checkpoint rather than undone
+    /// through a delta.
+    pub fn needs_checkpoint(opcode: Opcode) -> bool {
+        matches!(
+            opcode,
+            Opcode::ALOC
+                | Opcode::FREE
+                | Opcode::REALLOC
+                | Opcode::GC
+                | Opcode::SYSCALL
+                | Opcode::SPAWN
+                | Opcode::EXIT
+        )
+    }
+
+    /// Number of instructions that can be stepped back over.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.steps.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|segment| segment.steps.is_empty())
+    }
+
+    pub fn checkpoints(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Approximate memory held by the recording, in bytes.
+    pub fn memory_used(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether the next instruction should be preceded by a checkpoint.
+    pub fn wants_checkpoint(&self, opcode: Opcode) -> bool {
+        History::needs_checkpoint(opcode)
+            || self
+                .segments
+                .back()
+                .is_none_or(|segment| segment.steps.len() >= self.checkpoint_interval)
+    }
+
+    /// Starts recording an instruction. `checkpoint` must be given when
+    /// `wants_checkpoint` asked for one; the instruction is then undone by
+    /// restoring it if `needs_checkpoint` holds for the opcode.
+    pub fn begin(&mut self, opcode: Opcode, checkpoint: Option<Snapshot>, delta: Delta) {
+        if let Some(checkpoint) = checkpoint {
+            let bytes = size_of::<Segment>()
+                + checkpoint.program.len()
+                + checkpoint.heap.len()
+                + checkpoint.stack.len() * size_of::<i32>()
+                + checkpoint.allocator.blocks().count() * 2 * size_of::<usize>();
+            self.bytes += bytes;
+            self.segments.push_back(Segment {
+                checkpoint,
+                steps: vec![],
+                bytes,
+            });
+        }
+        let segment = self.segments.back_mut().expect("history has a segment");
+        if History::needs_checkpoint(opcode) && segment.steps.is_empty() {
+            segment.steps.push(None);
+        } else {
+            segment.steps.push(Some(delta));
+        }
+        self.trim();
+    }
+
+    /// Drops the instruction being recorded, e.g. because it trapped.
+    pub fn discard(&mut self) {
+        if let Some(segment) = self.segments.back_mut() {
+            segment.steps.pop();
+            if segment.steps.is_empty() {
+                let segment = self.segments.pop_back().expect("segment exists");
+                self.bytes -= segment.bytes;
+            }
+        }
+    }
+
+    fn current(&mut self) -> Option<&mut Delta> {
+        self.segments
+            .back_mut()
+            .and_then(|segment| segment.steps.last_mut())
+            .and_then(|step| step.as_mut())
+    }
+
+    pub fn record_register(&mut self, register: usize, old: i32) {
+        if let Some(delta) = self.current() {
+            delta.registers.push((register, old));
+        }
+    }
+
+    pub fn record_float_register(&mut self, register: usize, old: f64) {
+        if let Some(delta) = self.current() {
+            delta.float_registers.push((register, old));
+        }
+    }
+
+    pub fn record_heap(&mut self, address: usize, old: &[u8]) {
+        if let Some(delta) = self.current() {
+            delta.heap.push((address, old.to_vec()));
+        }
+    }
+
+    pub fn record_pop(&mut self, value: i32) {
+        if let Some(delta) = self.current() {
+            delta.popped.push(value);
+        }
+    }
+
+    /// Finishes recording an instruction, accounting for the memory its
+    /// delta took.
+    pub fn end(&mut self) {
+        if let Some(segment) = self.segments.back_mut() {
+            if let Some(Some(delta)) = segment.steps.last() {
+                let size = delta.size();
+                segment.bytes += size;
+                self.bytes += size;
+            }
+        }
+        self.trim();
+    }
+
+    /// Takes the most recent instruction off the log. Returns the checkpoint
+    /// to restore or the delta to undo, or `None` if the log is empty.
+    pub fn pop(&mut self) -> Option<Undo> {
+        loop {
+            let segment = self.segments.back_mut()?;
+            match segment.steps.pop() {
+                Some(Some(delta)) => {
+                    let size = delta.size();
+                    segment.bytes -= size;
+                    self.bytes -= size;
+                    return Some(Undo::Delta(delta));
+                }
+                Some(None) => {
+                    let segment = self.segments.pop_back().expect("segment exists");
+                    self.bytes -= segment.bytes;
+                    return Some(Undo::Checkpoint(Box::new(segment.checkpoint)));
+                }
+                None if self.segments.len() > 1 => {
+                    let segment = self.segments.pop_back().expect("segment exists");
+                    self.bytes -= segment.bytes;
+                }
+                None => return None,
+            }
+        }
+    }
+
+    /// Forgets everything recorded so far.
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.bytes = 0;
+    }
+
+    /// The earliest state still reachable, and forgets everything after
+    /// it.
+    pub fn rewind(&mut self) -> Option<Snapshot> {
+        while self.segments.len() > 1 {
+            self.segments.pop_back();
+        }
+        let segment = self.segments.pop_back()?;
+        self.bytes = 0;
+        Some(segment.checkpoint)
+    }
+
+    fn trim(&mut self) {
+        while self.bytes > self.memory_limit && self.segments.len() > 1 {
+            let segment = self.segments.pop_front().expect("segment exists");
+            self.bytes -= segment.bytes;
+        }
+    }
+}
+
+/// How to take back one recorded instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Undo {
+    Delta(Delta),
+    Checkpoint(Box<Snapshot>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::FIRST_BLOCK;
+    use crate::vm::{ExitReason, VM};
+
+    fn recording_vm(program: Vec<u8>) -> VM {
+        let mut vm = VM::new();
+        vm.history = Some(History::new());
+        vm.program = program;
+        vm
+    }
+
+    #[test]
+    fn test_step_back_restores_every_instruction() {
+        // SET $0 #8; ALOC $0 $1; STOREW $0 $1 $2; PUSH $0; POP $3; FREE $1; DIV $0 $4 $0
+        let mut vm = recording_vm(vec![
+            0, 0, 0, 8, 18, 0, 1, 0, 30, 0, 1, 2, 21, 0, 0, 0, 22, 3, 0, 0, 31, 1, 0, 0, 4, 0, 4, 0,
+        ]);
+        vm.registers[0] = 3;
+        let mut states = vec![vm.snapshot()];
+        while vm.pcounter < 24 {
+            vm.run_once().unwrap();
+            states.push(vm.snapshot());
+        }
+        assert!(vm.heap.len() <= FIRST_BLOCK);
+        // The trapping DIV leaves nothing behind to undo.
+        assert!(vm.run_once().is_err());
+        assert_eq!(vm.history.as_ref().unwrap().len(), 6);
+        states.pop();
+        while let Some(state) = states.pop() {
+            assert!(vm.step_back());
+            assert_eq!(vm.snapshot(), state);
+        }
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn test_periodic_checkpoints_and_memory_limit() {
+        // INC $0 forever.
+        let mut vm = recording_vm(vec![19, 0, 0, 0, 6, 1, 0, 0]);
+        let history = vm.history.as_mut().unwrap();
+        history.checkpoint_interval = 10;
+        history.memory_limit = 16 * 1024;
+        assert_eq!(vm.run_with_budget(1000), Ok(ExitReason::OutOfFuel));
+        let history = vm.history.as_ref().unwrap();
+        assert!(history.memory_used() <= 16 * 1024);
+        assert!(history.checkpoints() > 1);
+        let recorded = history.len();
+        assert!(recorded < 1000);
+        for _ in 0..recorded {
+            assert!(vm.step_back());
+        }
+        assert!(!vm.step_back());
+        assert_eq!(vm.registers[0], 500 - recorded as i32 / 2);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut vm = recording_vm(vec![19, 0, 0, 0, 19, 0, 0, 0, 19, 0, 0, 0]);
+        vm.history.as_mut().unwrap().checkpoint_interval = 2;
+        vm.run().unwrap();
+        assert_eq!(vm.registers[0], 3);
+        assert!(vm.rewind());
+        assert_eq!(vm.registers[0], 0);
+        assert_eq!(vm.pcounter, 0);
+        assert!(vm.history.as_ref().unwrap().is_empty());
+        assert!(!vm.rewind());
+    }
+}
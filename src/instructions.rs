@@ -23,6 +23,83 @@ pub enum Opcode {
     ALOC,
     INC,
     DEC,
+    PUSH,
+    POP,
+    CALL,
+    RET,
+    LOADB,
+    LOADH,
+    LOADW,
+    STOREB,
+    STOREH,
+    STOREW,
+    FREE,
+    REALLOC,
+    GC,
+    FSET,
+    FADD,
+    FSUB,
+    FMUL,
+    FDIV,
+    FCMP,
+    ITOF,
+    FTOI,
+    AND,
+    OR,
+    XOR,
+    NOT,
+    SHL,
+    SHR,
+    SAR,
+    CADD,
+    CSUB,
+    CMUL,
+    JC,
+    JNC,
+    JO,
+    JNO,
+    ADDI,
+    SUBI,
+    MULI,
+    DIVI,
+    EQI,
+    NEQI,
+    GTI,
+    LTI,
+    GTQI,
+    LTQI,
+    JGT,
+    JLT,
+    JGE,
+    JLE,
+    JGTR,
+    JLTR,
+    JGER,
+    JLER,
+    CMP,
+    CMPI,
+    GAS,
+    SYSCALL,
+    PRTI,
+    PRTC,
+    PRTS,
+    READI,
+    READC,
+    READLN,
+    FOPEN,
+    FREAD,
+    FWRITE,
+    FSEEK,
+    FCLOSE,
+    SLISTEN,
+    SACCEPT,
+    SCONNECT,
+    SSEND,
+    SRECV,
+    SCLOSE,
+    SPAWN,
+    YIELD,
+    EXIT,
     IGL
 }
 
@@ -37,6 +114,55 @@ impl Instruction {
     }
 }
 
+impl Opcode {
+    /// How many bytes after the opcode byte the VM reads when executing it.
+    pub fn operand_bytes(self) -> usize {
+        match self {
+            Opcode::HLT | Opcode::RET | Opcode::IGL => 0,
+            Opcode::JMP | Opcode::JMPF | Opcode::JMPB => 1,
+            Opcode::FSET => 11,
+            _ => 3,
+        }
+    }
+
+    /// The register-immediate form of an arithmetic or comparison opcode.
+    pub fn immediate_form(self) -> Option<Opcode> {
+        match self {
+            Opcode::ADD => Some(Opcode::ADDI),
+            Opcode::SUB => Some(Opcode::SUBI),
+            Opcode::MUL => Some(Opcode::MULI),
+            Opcode::DIV => Some(Opcode::DIVI),
+            Opcode::EQ => Some(Opcode::EQI),
+            Opcode::NEQ => Some(Opcode::NEQI),
+            Opcode::GT => Some(Opcode::GTI),
+            Opcode::LT => Some(Opcode::LTI),
+            Opcode::GTQ => Some(Opcode::GTQI),
+            Opcode::LTQ => Some(Opcode::LTQI),
+            Opcode::CMP => Some(Opcode::CMPI),
+            _ => None,
+        }
+    }
+
+    /// The register-register form of an immediate opcode; other opcodes are
+    /// returned unchanged.
+    pub fn register_form(self) -> Opcode {
+        match self {
+            Opcode::ADDI => Opcode::ADD,
+            Opcode::SUBI => Opcode::SUB,
+            Opcode::MULI => Opcode::MUL,
+            Opcode::DIVI => Opcode::DIV,
+            Opcode::EQI => Opcode::EQ,
+            Opcode::NEQI => Opcode::NEQ,
+            Opcode::GTI => Opcode::GT,
+            Opcode::LTI => Opcode::LT,
+            Opcode::GTQI => Opcode::GTQ,
+            Opcode::LTQI => Opcode::LTQ,
+            Opcode::CMPI => Opcode::CMP,
+            other => other,
+        }
+    }
+}
+
 impl From<u8> for Opcode {
     fn from(v: u8) -> Self {
         match v {
@@ -61,6 +187,83 @@ impl From<u8> for Opcode {
             18 => Opcode::ALOC,
             19 => Opcode::INC,
             20 => Opcode::DEC,
+            21 => Opcode::PUSH,
+            22 => Opcode::POP,
+            23 => Opcode::CALL,
+            24 => Opcode::RET,
+            25 => Opcode::LOADB,
+            26 => Opcode::LOADH,
+            27 => Opcode::LOADW,
+            28 => Opcode::STOREB,
+            29 => Opcode::STOREH,
+            30 => Opcode::STOREW,
+            31 => Opcode::FREE,
+            32 => Opcode::REALLOC,
+            33 => Opcode::GC,
+            34 => Opcode::FSET,
+            35 => Opcode::FADD,
+            36 => Opcode::FSUB,
+            37 => Opcode::FMUL,
+            38 => Opcode::FDIV,
+            39 => Opcode::FCMP,
+            40 => Opcode::ITOF,
+            41 => Opcode::FTOI,
+            42 => Opcode::AND,
+            43 => Opcode::OR,
+            44 => Opcode::XOR,
+            45 => Opcode::NOT,
+            46 => Opcode::SHL,
+            47 => Opcode::SHR,
+            48 => Opcode::SAR,
+            49 => Opcode::CADD,
+            50 => Opcode::CSUB,
+            51 => Opcode::CMUL,
+            52 => Opcode::JC,
+            53 => Opcode::JNC,
+            54 => Opcode::JO,
+            55 => Opcode::JNO,
+            56 => Opcode::ADDI,
+            57 => Opcode::SUBI,
+            58 => Opcode::MULI,
+            59 => Opcode::DIVI,
+            60 => Opcode::EQI,
+            61 => Opcode::NEQI,
+            62 => Opcode::GTI,
+            63 => Opcode::LTI,
+            64 => Opcode::GTQI,
+            65 => Opcode::LTQI,
+            66 => Opcode::JGT,
+            67 => Opcode::JLT,
+            68 => Opcode::JGE,
+            69 => Opcode::JLE,
+            70 => Opcode::JGTR,
+            71 => Opcode::JLTR,
+            72 => Opcode::JGER,
+            73 => Opcode::JLER,
+            74 => Opcode::CMP,
+            75 => Opcode::CMPI,
+            76 => Opcode::GAS,
+            77 => Opcode::SYSCALL,
+            78 => Opcode::PRTI,
+            79 => Opcode::PRTC,
+            80 => Opcode::PRTS,
+            81 => Opcode::READI,
+            82 => Opcode::READC,
+            83 => Opcode::READLN,
+            84 => Opcode::FOPEN,
+            85 => Opcode::FREAD,
+            86 => Opcode::FWRITE,
+            87 => Opcode::FSEEK,
+            88 => Opcode::FCLOSE,
+            89 => Opcode::SLISTEN,
+            90 => Opcode::SACCEPT,
+            91 => Opcode::SCONNECT,
+            92 => Opcode::SSEND,
+            93 => Opcode::SRECV,
+            94 => Opcode::SCLOSE,
+            95 => Opcode::SPAWN,
+            96 => Opcode::YIELD,
+            97 => Opcode::EXIT,
             100 => Opcode::IGL,
             _ => Opcode::IGL
         }
@@ -91,6 +294,83 @@ impl From<Opcode> for u8 {
             Opcode::ALOC => 18,
             Opcode::INC => 19,
             Opcode::DEC => 20,
+            Opcode::PUSH => 21,
+            Opcode::POP => 22,
+            Opcode::CALL => 23,
+            Opcode::RET => 24,
+            Opcode::LOADB => 25,
+            Opcode::LOADH => 26,
+            Opcode::LOADW => 27,
+            Opcode::STOREB => 28,
+            Opcode::STOREH => 29,
+            Opcode::STOREW => 30,
+            Opcode::FREE => 31,
+            Opcode::REALLOC => 32,
+            Opcode::GC => 33,
+            Opcode::FSET => 34,
+            Opcode::FADD => 35,
+            Opcode::FSUB => 36,
+            Opcode::FMUL => 37,
+            Opcode::FDIV => 38,
+            Opcode::FCMP => 39,
+            Opcode::ITOF => 40,
+            Opcode::FTOI => 41,
+            Opcode::AND => 42,
+            Opcode::OR => 43,
+            Opcode::XOR => 44,
+            Opcode::NOT => 45,
+            Opcode::SHL => 46,
+            Opcode::SHR => 47,
+            Opcode::SAR => 48,
+            Opcode::CADD => 49,
+            Opcode::CSUB => 50,
+            Opcode::CMUL => 51,
+            Opcode::JC => 52,
+            Opcode::JNC => 53,
+            Opcode::JO => 54,
+            Opcode::JNO => 55,
+            Opcode::ADDI => 56,
+            Opcode::SUBI => 57,
+            Opcode::MULI => 58,
+            Opcode::DIVI => 59,
+            Opcode::EQI => 60,
+            Opcode::NEQI => 61,
+            Opcode::GTI => 62,
+            Opcode::LTI => 63,
+            Opcode::GTQI => 64,
+            Opcode::LTQI => 65,
+            Opcode::JGT => 66,
+            Opcode::JLT => 67,
+            Opcode::JGE => 68,
+            Opcode::JLE => 69,
+            Opcode::JGTR => 70,
+            Opcode::JLTR => 71,
+            Opcode::JGER => 72,
+            Opcode::JLER => 73,
+            Opcode::CMP => 74,
+            Opcode::CMPI => 75,
+            Opcode::GAS => 76,
+            Opcode::SYSCALL => 77,
+            Opcode::PRTI => 78,
+            Opcode::PRTC => 79,
+            Opcode::PRTS => 80,
+            Opcode::READI => 81,
+            Opcode::READC => 82,
+            Opcode::READLN => 83,
+            Opcode::FOPEN => 84,
+            Opcode::FREAD => 85,
+            Opcode::FWRITE => 86,
+            Opcode::FSEEK => 87,
+            Opcode::FCLOSE => 88,
+            Opcode::SLISTEN => 89,
+            Opcode::SACCEPT => 90,
+            Opcode::SCONNECT => 91,
+            Opcode::SSEND => 92,
+            Opcode::SRECV => 93,
+            Opcode::SCLOSE => 94,
+            Opcode::SPAWN => 95,
+            Opcode::YIELD => 96,
+            Opcode::EXIT => 97,
             Opcode::IGL => 100,
         }
     }
@@ -121,6 +401,83 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("aloc") => Opcode::ALOC,
             CompleteStr("inc") => Opcode::INC,
             CompleteStr("dec") => Opcode::DEC,
+            CompleteStr("push") => Opcode::PUSH,
+            CompleteStr("pop") => Opcode::POP,
+            CompleteStr("call") => Opcode::CALL,
+            CompleteStr("ret") => Opcode::RET,
+            CompleteStr("loadb") => Opcode::LOADB,
+            CompleteStr("loadh") => Opcode::LOADH,
+            CompleteStr("loadw") => Opcode::LOADW,
+            CompleteStr("storeb") => Opcode::STOREB,
+            CompleteStr("storeh") => Opcode::STOREH,
+            CompleteStr("storew") => Opcode::STOREW,
+            CompleteStr("free") => Opcode::FREE,
+            CompleteStr("realloc") => Opcode::REALLOC,
+            CompleteStr("gc") => Opcode::GC,
+            CompleteStr("fset") => Opcode::FSET,
+            CompleteStr("fadd") => Opcode::FADD,
+            CompleteStr("fsub") => Opcode::FSUB,
+            CompleteStr("fmul") => Opcode::FMUL,
+            CompleteStr("fdiv") => Opcode::FDIV,
+            CompleteStr("fcmp") => Opcode::FCMP,
+            CompleteStr("itof") => Opcode::ITOF,
+            CompleteStr("ftoi") => Opcode::FTOI,
+            CompleteStr("and") => Opcode::AND,
+            CompleteStr("or") => Opcode::OR,
+            CompleteStr("xor") => Opcode::XOR,
+            CompleteStr("not") => Opcode::NOT,
+            CompleteStr("shl") => Opcode::SHL,
+            CompleteStr("shr") => Opcode::SHR,
+            CompleteStr("sar") => Opcode::SAR,
+            CompleteStr("cadd") => Opcode::CADD,
+            CompleteStr("csub") => Opcode::CSUB,
+            CompleteStr("cmul") => Opcode::CMUL,
+            CompleteStr("jc") => Opcode::JC,
+            CompleteStr("jnc") => Opcode::JNC,
+            CompleteStr("jo") => Opcode::JO,
+            CompleteStr("jno") => Opcode::JNO,
+            CompleteStr("addi") => Opcode::ADDI,
+            CompleteStr("subi") => Opcode::SUBI,
+            CompleteStr("muli") => Opcode::MULI,
+            CompleteStr("divi") => Opcode::DIVI,
+            CompleteStr("eqi") => Opcode::EQI,
+            CompleteStr("neqi") => Opcode::NEQI,
+            CompleteStr("gti") => Opcode::GTI,
+            CompleteStr("lti") => Opcode::LTI,
+            CompleteStr("gtqi") => Opcode::GTQI,
+            CompleteStr("ltqi") => Opcode::LTQI,
+            CompleteStr("jgt") => Opcode::JGT,
+            CompleteStr("jlt") => Opcode::JLT,
+            CompleteStr("jge") => Opcode::JGE,
+            CompleteStr("jle") => Opcode::JLE,
+            CompleteStr("jgtr") => Opcode::JGTR,
+            CompleteStr("jltr") => Opcode::JLTR,
+            CompleteStr("jger") => Opcode::JGER,
+            CompleteStr("jler") => Opcode::JLER,
+            CompleteStr("cmp") => Opcode::CMP,
+            CompleteStr("cmpi") => Opcode::CMPI,
+            CompleteStr("gas") => Opcode::GAS,
+            CompleteStr("syscall") => Opcode::SYSCALL,
+            CompleteStr("prti") => Opcode::PRTI,
+            CompleteStr("prtc") => Opcode::PRTC,
+            CompleteStr("prts") => Opcode::PRTS,
+            CompleteStr("readi") => Opcode::READI,
+            CompleteStr("readc") => Opcode::READC,
+            CompleteStr("readln") => Opcode::READLN,
+            CompleteStr("fopen") => Opcode::FOPEN,
+            CompleteStr("fread") => Opcode::FREAD,
+            CompleteStr("fwrite") => Opcode::FWRITE,
+            CompleteStr("fseek") => Opcode::FSEEK,
+            CompleteStr("fclose") => Opcode::FCLOSE,
+            CompleteStr("slisten") => Opcode::SLISTEN,
+            CompleteStr("saccept") => Opcode::SACCEPT,
+            CompleteStr("sconnect") => Opcode::SCONNECT,
+            CompleteStr("ssend") => Opcode::SSEND,
+            CompleteStr("srecv") => Opcode::SRECV,
+            CompleteStr("sclose") => Opcode::SCLOSE,
+            CompleteStr("spawn") => Opcode::SPAWN,
+            CompleteStr("yield") => Opcode::YIELD,
+            CompleteStr("exit") => Opcode::EXIT,
             _ => Opcode::IGL
         }
     }
@@ -154,12 +511,22 @@ mod tests {
         assert_eq!(opcode as u8, 0);
     }
 
+    #[test]
+    fn test_immediate_form() {
+        assert_eq!(Opcode::ADD.immediate_form(), Some(Opcode::ADDI));
+        assert_eq!(Opcode::JMP.immediate_form(), None);
+        assert_eq!(Opcode::LTQI.register_form(), Opcode::LTQ);
+        assert_eq!(Opcode::JMP.register_form(), Opcode::JMP);
+    }
+
     #[test]
     fn test_str_to_opcode() {
         let opcode = Opcode::from(CompleteStr("set"));
         assert_eq!(opcode, Opcode::SET);
         let opcode = Opcode::from(CompleteStr("SET"));
         assert_eq!(opcode, Opcode::SET);
+        let opcode = Opcode::from(CompleteStr("call"));
+        assert_eq!(opcode, Opcode::CALL);
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
@@ -1,13 +1,35 @@
 #[macro_use]
 extern crate nom;
+#[macro_use]
+extern crate log;
 
 pub mod vm;
+pub mod trap;
+pub mod flags;
+pub mod allocator;
+pub mod gc;
+pub mod gas;
+pub mod snapshot;
+pub mod history;
+pub mod syscall;
+pub mod console;
+pub mod files;
+pub mod net;
+pub mod scheduler;
+pub mod persist;
+pub mod observer;
+pub mod debugger;
+pub mod watchpoint;
 pub mod instructions;
 pub mod repl;
 pub mod asm;
 
 fn main() {
-    let vm = vm::VM::new();
+    env_logger::init();
+    let mut vm = vm::VM::new();
+    if log_enabled!(log::Level::Trace) {
+        vm.observers.push(Box::new(observer::LogObserver));
+    }
     let mut repl = repl::REPL::new(vm);
     repl.run();
 }
@@ -0,0 +1,282 @@
+//! TCP sockets reachable from bytecode through `SLISTEN` and friends.
+//!
+//! Addresses are IPv4, passed as a big-endian `u32` in a register along
+//! with a port. Every socket is non-blocking: an operation that would
+//! wait stops the VM with `ExitReason::Blocked` at the same instruction,
+//! so the host or a scheduler can run something else and retry it later.
+//! `SCONNECT` itself never waits: it hands back a descriptor at once, and
+//! sending or receiving on it blocks until the connection is up.
+
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use crate::files::FIRST_SOCKET_DESCRIPTOR;
+
+/// Which addresses programs may listen on and connect to.
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum NetPolicy {
+    /// No networking at all.
+    #[default]
+    Disabled,
+    LoopbackOnly,
+    Unrestricted,
+}
+
+impl NetPolicy {
+    pub fn allows(self, address: Ipv4Addr) -> bool {
+        match self {
+            NetPolicy::Disabled => false,
+            NetPolicy::LoopbackOnly => address.is_loopback(),
+            NetPolicy::Unrestricted => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NetError {
+    /// The policy does not allow the address.
+    Denied(SocketAddrV4),
+    /// No socket of the right kind has this descriptor.
+    BadDescriptor(i32),
+    /// The operation would have to wait.
+    WouldBlock,
+    Io(io::Error),
+}
+
+impl From<io::Error> for NetError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::WouldBlock {
+            NetError::WouldBlock
+        } else {
+            NetError::Io(error)
+        }
+    }
+}
+
+/// How long a connection attempt may take before it fails.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+enum Socket {
+    Listener(TcpListener),
+    Stream(TcpStream),
+    /// A connection still being made on a background thread.
+    Connecting(Receiver<io::Result<TcpStream>>),
+}
+
+/// The sockets a VM has open and the policy that governs new ones.
+#[derive(Debug)]
+pub struct SocketTable {
+    pub policy: NetPolicy,
+    sockets: BTreeMap<i32, Socket>,
+    next_descriptor: i32,
+}
+
+impl Default for SocketTable {
+    fn default() -> Self {
+        SocketTable::new()
+    }
+}
+
+impl SocketTable {
+    /// A table whose policy refuses every address.
+    pub fn new() -> Self {
+        SocketTable::with_policy(NetPolicy::Disabled)
+    }
+
+    pub fn with_policy(policy: NetPolicy) -> Self {
+        SocketTable {
+            policy,
+            sockets: BTreeMap::new(),
+            next_descriptor: FIRST_SOCKET_DESCRIPTOR,
+        }
+    }
+
+    fn check(&self, address: i32, port: i32) -> Result<SocketAddrV4, NetError> {
+        let address = SocketAddrV4::new(Ipv4Addr::from(address as u32), port as u16);
+        if port < 0 || port > u16::MAX as i32 || !self.policy.allows(*address.ip()) {
+            return Err(NetError::Denied(address));
+        }
+        Ok(address)
+    }
+
+    /// Stores `socket` under the next descriptor not in use.
+    fn insert(&mut self, socket: Socket) -> i32 {
+        loop {
+            let descriptor = self.next_descriptor;
+            self.next_descriptor = descriptor.checked_add(1).unwrap_or(FIRST_SOCKET_DESCRIPTOR);
+            if let Entry::Vacant(entry) = self.sockets.entry(descriptor) {
+                entry.insert(socket);
+                return descriptor;
+            }
+        }
+    }
+
+    /// Listens on `address` and `port`; port 0 picks a free one. Returns the
+    /// descriptor and the port actually bound.
+    pub fn listen(&mut self, address: i32, port: i32) -> Result<(i32, u16), NetError> {
+        let address = self.check(address, port)?;
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        Ok((self.insert(Socket::Listener(listener)), port))
+    }
+
+    /// Takes the next pending connection off a listener.
+    pub fn accept(&mut self, listener: i32) -> Result<i32, NetError> {
+        let (stream, _) = match self.sockets.get(&listener) {
+            Some(Socket::Listener(listener)) => listener.accept()?,
+            _ => return Err(NetError::BadDescriptor(listener)),
+        };
+        stream.set_nonblocking(true)?;
+        Ok(self.insert(Socket::Stream(stream)))
+    }
+
+    /// Starts connecting to `address` and `port` and returns the new
+    /// socket's descriptor without waiting.
+    ///
+    /// The standard library has no non-blocking connect, so the connection
+    /// is made on a background thread that gives up after
+    /// `CONNECT_TIMEOUT`. Until it is up, `send` and `recv` on the
+    /// descriptor report `WouldBlock`; if it fails, the first of them
+    /// returns the error and the descriptor is closed.
+    pub fn connect(&mut self, address: i32, port: i32) -> Result<i32, NetError> {
+        let address = SocketAddr::V4(self.check(address, port)?);
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            // The receiver is gone if the socket was closed meanwhile.
+            let _ = sender.send(TcpStream::connect_timeout(&address, CONNECT_TIMEOUT));
+        });
+        Ok(self.insert(Socket::Connecting(receiver)))
+    }
+
+    /// Turns a `Connecting` socket into a stream once its connection is up.
+    fn finish_connecting(&mut self, descriptor: i32) -> Result<(), NetError> {
+        let result = match self.sockets.get(&descriptor) {
+            Some(Socket::Connecting(pending)) => match pending.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return Err(NetError::WouldBlock),
+                Err(TryRecvError::Disconnected) => Err(io::Error::other("connection attempt was abandoned")),
+            },
+            _ => return Ok(()),
+        };
+        let stream = result.and_then(|stream| stream.set_nonblocking(true).map(|()| stream));
+        match stream {
+            Ok(stream) => {
+                self.sockets.insert(descriptor, Socket::Stream(stream));
+                Ok(())
+            }
+            Err(error) => {
+                self.sockets.remove(&descriptor);
+                Err(NetError::Io(error))
+            }
+        }
+    }
+
+    fn stream(&mut self, descriptor: i32) -> Result<&mut TcpStream, NetError> {
+        self.finish_connecting(descriptor)?;
+        match self.sockets.get_mut(&descriptor) {
+            Some(Socket::Stream(stream)) => Ok(stream),
+            _ => Err(NetError::BadDescriptor(descriptor)),
+        }
+    }
+
+    /// Sends as much of `bytes` as fits and returns how much that was.
+    pub fn send(&mut self, descriptor: i32, bytes: &[u8]) -> Result<usize, NetError> {
+        Ok(self.stream(descriptor)?.write(bytes)?)
+    }
+
+    /// Receives up to `buf.len()` bytes; 0 means the peer closed.
+    pub fn recv(&mut self, descriptor: i32, buf: &mut [u8]) -> Result<usize, NetError> {
+        Ok(self.stream(descriptor)?.read(buf)?)
+    }
+
+    pub fn close(&mut self, descriptor: i32) -> Result<(), NetError> {
+        self.sockets.remove(&descriptor).map(drop).ok_or(NetError::BadDescriptor(descriptor))
+    }
+
+    pub fn close_all(&mut self) {
+        self.sockets.clear();
+    }
+
+    pub fn descriptors(&self) -> impl Iterator<Item = i32> + '_ {
+        self.sockets.keys().copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOPBACK: i32 = 0x7F00_0001;
+
+    #[test]
+    fn test_policy() {
+        let mut sockets = SocketTable::new();
+        assert!(matches!(sockets.listen(LOOPBACK, 0), Err(NetError::Denied(_))));
+        sockets.policy = NetPolicy::LoopbackOnly;
+        assert!(matches!(sockets.connect(0x0A00_0001, 80), Err(NetError::Denied(_))));
+        assert!(matches!(sockets.listen(LOOPBACK, 70000), Err(NetError::Denied(_))));
+        assert!(NetPolicy::Unrestricted.allows(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+
+    #[test]
+    fn test_descriptors_skip_open_sockets_when_wrapping() {
+        let mut sockets = SocketTable::with_policy(NetPolicy::LoopbackOnly);
+        let (first, _) = sockets.listen(LOOPBACK, 0).unwrap();
+        sockets.next_descriptor = i32::MAX;
+        assert_eq!(sockets.listen(LOOPBACK, 0).unwrap().0, i32::MAX);
+        assert_eq!(sockets.listen(LOOPBACK, 0).unwrap().0, first + 1);
+    }
+
+    #[test]
+    fn test_failed_connect() {
+        let mut sockets = SocketTable::with_policy(NetPolicy::LoopbackOnly);
+        let (listener, port) = sockets.listen(LOOPBACK, 0).unwrap();
+        sockets.close(listener).unwrap();
+        let client = sockets.connect(LOOPBACK, port as i32).unwrap();
+        let error = loop {
+            match sockets.send(client, b"x") {
+                Err(NetError::WouldBlock) => thread::yield_now(),
+                result => break result.unwrap_err(),
+            }
+        };
+        assert!(matches!(error, NetError::Io(_)));
+        assert!(matches!(sockets.close(client), Err(NetError::BadDescriptor(_))));
+    }
+
+    #[test]
+    fn test_loopback_round_trip() {
+        let mut sockets = SocketTable::with_policy(NetPolicy::LoopbackOnly);
+        let (listener, port) = sockets.listen(LOOPBACK, 0).unwrap();
+        assert_eq!(listener, FIRST_SOCKET_DESCRIPTOR);
+        assert!(matches!(sockets.accept(listener), Err(NetError::WouldBlock)));
+        let client = sockets.connect(LOOPBACK, port as i32).unwrap();
+        let server = loop {
+            match sockets.accept(listener) {
+                Err(NetError::WouldBlock) => thread::yield_now(),
+                result => break result.unwrap(),
+            }
+        };
+        let mut buf = [0; 8];
+        assert!(matches!(sockets.recv(server, &mut buf), Err(NetError::WouldBlock)));
+        let sent = loop {
+            match sockets.send(client, b"ping") {
+                Err(NetError::WouldBlock) => thread::yield_now(),
+                result => break result.unwrap(),
+            }
+        };
+        assert_eq!(sent, 4);
+        assert_eq!(sockets.recv(server, &mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"ping");
+        assert!(matches!(sockets.send(listener, b"x"), Err(NetError::BadDescriptor(_))));
+        sockets.close(client).unwrap();
+        assert_eq!(sockets.recv(server, &mut buf).unwrap(), 0);
+        assert_eq!(sockets.descriptors().collect::<Vec<_>>(), vec![listener, server]);
+    }
+}
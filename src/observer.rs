@@ -0,0 +1,186 @@
+use std::fmt;
+
+use crate::instructions::Opcode;
+use crate::trap::{ExitReason, VmTrap};
+
+/// An instruction as it appears in the program, split into its opcode and
+/// the operand bytes the VM reads for it.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct DecodedInstruction<'a> {
+    pub pc: usize,
+    pub opcode: Opcode,
+    /// Cut short if the instruction runs off the end of the program.
+    pub operands: &'a [u8],
+}
+
+impl<'a> DecodedInstruction<'a> {
+    /// Decodes the instruction at `pc`, or returns `None` at the end of the
+    /// program.
+    pub fn decode(program: &'a [u8], pc: usize) -> Option<Self> {
+        let opcode = Opcode::from(*program.get(pc)?);
+        let end = (pc + 1 + opcode.operand_bytes()).min(program.len());
+        Some(DecodedInstruction {
+            pc,
+            opcode,
+            operands: &program[pc + 1..end],
+        })
+    }
+}
+
+impl fmt::Display for DecodedInstruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}: {:?}", self.pc, self.opcode)?;
+        for byte in self.operands {
+            write!(f, " {:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hooks into the VM's execution. Every method has an empty default, so
+/// an observer only implements the events it cares about.
+///
+/// `after_instruction` is only called for instructions that completed;
+/// one that traps is followed by `on_trap` instead, and leaves no register
+/// or heap writes behind. Heap accesses are those of the instructions
+/// themselves: `LOAD*` and `STORE*`, buffers written by `READLN`, `FREAD`
+/// and `SRECV`, and strings or buffers read by `PRTS`, `FOPEN`, `FWRITE`
+/// and `SSEND`. Host functions called through `SYSCALL` touch the heap
+/// directly, and neither they nor the allocator zeroing or moving blocks
+/// are reported.
+pub trait VmObserver {
+    fn before_instruction(&mut self, _instruction: &DecodedInstruction) {}
+    fn after_instruction(&mut self, _instruction: &DecodedInstruction) {}
+    fn register_read(&mut self, _register: usize, _value: i32) {}
+    fn float_register_read(&mut self, _register: usize, _value: f64) {}
+    fn register_write(&mut self, _register: usize, _old: i32, _new: i32) {}
+    fn float_register_write(&mut self, _register: usize, _old: f64, _new: f64) {}
+    fn heap_read(&mut self, _address: usize, _bytes: &[u8]) {}
+    fn heap_write(&mut self, _address: usize, _old: &[u8], _new: &[u8]) {}
+    /// Called when `HLT` executes or the program runs off its end.
+    fn on_halt(&mut self, _pc: usize, _reason: ExitReason) {}
+    fn on_trap(&mut self, _trap: &VmTrap) {}
+    /// Asked after every instruction; returning true makes the VM stop with
+    /// `ExitReason::Paused` before the next one.
+    fn pause_requested(&mut self) -> bool {
+        false
+    }
+}
+
+/// The observers attached to a VM, notified in the order they were added.
+#[derive(Default)]
+pub struct ObserverList {
+    observers: Vec<Box<dyn VmObserver>>,
+}
+
+impl ObserverList {
+    pub fn new() -> Self {
+        ObserverList { observers: vec![] }
+    }
+
+    pub fn push(&mut self, observer: Box<dyn VmObserver>) {
+        self.observers.push(observer);
+    }
+
+    pub fn clear(&mut self) {
+        self.observers.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.observers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+}
+
+impl fmt::Debug for ObserverList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ObserverList({} observers)", self.observers.len())
+    }
+}
+
+impl VmObserver for ObserverList {
+    fn before_instruction(&mut self, instruction: &DecodedInstruction) {
+        self.observers.iter_mut().for_each(|o| o.before_instruction(instruction));
+    }
+    fn after_instruction(&mut self, instruction: &DecodedInstruction) {
+        self.observers.iter_mut().for_each(|o| o.after_instruction(instruction));
+    }
+    fn register_read(&mut self, register: usize, value: i32) {
+        self.observers.iter_mut().for_each(|o| o.register_read(register, value));
+    }
+    fn float_register_read(&mut self, register: usize, value: f64) {
+        self.observers.iter_mut().for_each(|o| o.float_register_read(register, value));
+    }
+    fn register_write(&mut self, register: usize, old: i32, new: i32) {
+        self.observers.iter_mut().for_each(|o| o.register_write(register, old, new));
+    }
+    fn float_register_write(&mut self, register: usize, old: f64, new: f64) {
+        self.observers.iter_mut().for_each(|o| o.float_register_write(register, old, new));
+    }
+    fn heap_read(&mut self, address: usize, bytes: &[u8]) {
+        self.observers.iter_mut().for_each(|o| o.heap_read(address, bytes));
+    }
+    fn heap_write(&mut self, address: usize, old: &[u8], new: &[u8]) {
+        self.observers.iter_mut().for_each(|o| o.heap_write(address, old, new));
+    }
+    fn on_halt(&mut self, pc: usize, reason: ExitReason) {
+        self.observers.iter_mut().for_each(|o| o.on_halt(pc, reason));
+    }
+    fn on_trap(&mut self, trap: &VmTrap) {
+        self.observers.iter_mut().for_each(|o| o.on_trap(trap));
+    }
+    fn pause_requested(&mut self) -> bool {
+        // Every observer is asked, so none is left with a stale request.
+        self.observers
+            .iter_mut()
+            .fold(false, |pause, o| o.pause_requested() | pause)
+    }
+}
+
+/// Traces execution through the `log` crate: instructions and writes at
+/// `trace`, halts at `debug` and traps at `warn`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct LogObserver;
+
+impl VmObserver for LogObserver {
+    fn before_instruction(&mut self, instruction: &DecodedInstruction) {
+        trace!("{}", instruction);
+    }
+    fn register_write(&mut self, register: usize, old: i32, new: i32) {
+        trace!("  ${} = {} (was {})", register, new, old);
+    }
+    fn float_register_write(&mut self, register: usize, old: f64, new: f64) {
+        trace!("  $f{} = {} (was {})", register, new, old);
+    }
+    fn heap_write(&mut self, address: usize, old: &[u8], new: &[u8]) {
+        trace!("  heap[{}] = {:02x?} (was {:02x?})", address, new, old);
+    }
+    fn on_halt(&mut self, pc: usize, reason: ExitReason) {
+        debug!("{:?} at {}", reason, pc);
+    }
+    fn on_trap(&mut self, trap: &VmTrap) {
+        warn!("trap: {}", trap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let program = vec![1, 0, 1, 2, 5, 34, 0, 0];
+        let add = DecodedInstruction::decode(&program, 0).unwrap();
+        assert_eq!(add.opcode, Opcode::ADD);
+        assert_eq!(add.operands, &[0, 1, 2]);
+        assert_eq!(add.to_string(), "0000: ADD 00 01 02");
+        let hlt = DecodedInstruction::decode(&program, 4).unwrap();
+        assert!(hlt.operands.is_empty());
+        let truncated = DecodedInstruction::decode(&program, 5).unwrap();
+        assert_eq!(truncated.operands, &[0, 0]);
+        assert_eq!(DecodedInstruction::decode(&program, 8), None);
+    }
+}
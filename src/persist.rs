@@ -0,0 +1,531 @@
+//! Binary save files for a `VM`.
+//!
+//! A file is the magic bytes `CRVM`, a big-endian `u16` format version, the
+//! encoded VM state and finally a CRC-32 of everything before it. All
+//! integers are big-endian; lengths and sizes are written as `u64`.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::allocator::{Allocator, Block};
+use crate::flags::Flags;
+use crate::gas::GasTable;
+use crate::gc::{Collector, GcReport, GcStats};
+use crate::scheduler::{Context, Process, ProcessState, Scheduler};
+use crate::snapshot::Snapshot;
+use crate::vm::VM;
+
+pub const MAGIC: &[u8; 4] = b"CRVM";
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum PersistError {
+    Io(io::Error),
+    /// The file does not start with `MAGIC`.
+    NotAVmFile,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    /// The data ended early or describes an impossible VM.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PersistError::Io(e) => write!(f, "i/o error: {}", e),
+            PersistError::NotAVmFile => write!(f, "not a VM save file"),
+            PersistError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save file version {}", version)
+            }
+            PersistError::ChecksumMismatch => write!(f, "save file checksum mismatch"),
+            PersistError::Corrupt(what) => write!(f, "corrupt save file: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for PersistError {}
+
+impl From<io::Error> for PersistError {
+    fn from(e: io::Error) -> Self {
+        PersistError::Io(e)
+    }
+}
+
+/// CRC-32 (IEEE 802.3, as used by zip and PNG).
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Encodes the complete state of `vm`, minus its observers.
+pub fn encode(vm: &VM) -> Vec<u8> {
+    let snapshot = vm.snapshot();
+    let mut out = Writer(MAGIC.to_vec());
+    out.u16(FORMAT_VERSION);
+    snapshot.registers.iter().for_each(|r| out.i32(*r));
+    snapshot.float_registers.iter().for_each(|r| out.f64(*r));
+    out.usize(snapshot.pcounter);
+    out.bytes(&snapshot.program);
+    out.u32(snapshot.remainder);
+    out.u8(snapshot.flags.bits());
+    out.bytes(&snapshot.heap);
+    out.usize(snapshot.allocator.blocks().count());
+    for (address, block) in snapshot.allocator.blocks() {
+        out.usize(address);
+        out.usize(block.size);
+        out.bool(block.free);
+    }
+    encode_collector(&mut out, &snapshot.gc);
+    out.usize(snapshot.stack.len());
+    snapshot.stack.iter().for_each(|value| out.i32(*value));
+    out.option(snapshot.fuel);
+    out.option(snapshot.gas);
+    out.usize(vm.heap_limit);
+    out.usize(vm.stack_limit);
+    vm.gas_costs.costs().iter().for_each(|cost| out.u64(*cost));
+    out.u64(vm.gas_costs.heap_byte_cost);
+    encode_scheduler(&mut out, &snapshot.scheduler);
+    let checksum = crc32(&out.0);
+    out.u32(checksum);
+    out.0
+}
+
+/// Decodes a VM written by `encode`. The result has no observers.
+pub fn decode(bytes: &[u8]) -> Result<VM, PersistError> {
+    if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(PersistError::NotAVmFile);
+    }
+    if bytes.len() < MAGIC.len() + 2 + 4 {
+        return Err(PersistError::Corrupt("file too short"));
+    }
+    let version = u16::from_be_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(PersistError::UnsupportedVersion(version));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32(body) != u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) {
+        return Err(PersistError::ChecksumMismatch);
+    }
+
+    let mut input = Reader(&body[MAGIC.len() + 2..]);
+    let mut registers = [0; 32];
+    for register in registers.iter_mut() {
+        *register = input.i32()?;
+    }
+    let mut float_registers = [0.0; 32];
+    for register in float_registers.iter_mut() {
+        *register = input.f64()?;
+    }
+    let pcounter = input.usize()?;
+    let program = input.bytes()?;
+    if pcounter > program.len() {
+        return Err(PersistError::Corrupt("program counter past end of program"));
+    }
+    let remainder = input.u32()?;
+    let flags = Flags::from_bits(input.u8()?);
+    let heap = input.bytes()?;
+    let mut blocks = vec![];
+    let mut end = 0;
+    for _ in 0..input.len()? {
+        let address = input.usize()?;
+        let size = input.usize()?;
+        let free = input.bool()?;
+        if address < end || address.checked_add(size).is_none_or(|block_end| block_end > heap.len()) {
+            return Err(PersistError::Corrupt("allocator block outside the heap"));
+        }
+        end = address + size;
+        blocks.push((address, Block { size, free }));
+    }
+    let gc = decode_collector(&mut input)?;
+    let mut stack = vec![];
+    for _ in 0..input.len()? {
+        stack.push(input.i32()?);
+    }
+    let mut snapshot = Snapshot {
+        registers,
+        float_registers,
+        pcounter,
+        program,
+        remainder,
+        flags,
+        heap,
+        allocator: Allocator::from_blocks(blocks),
+        gc,
+        stack,
+        fuel: input.option()?,
+        gas: input.option()?,
+        scheduler: Scheduler::new(),
+    };
+
+    let mut vm = VM::new();
+    vm.heap_limit = input.usize()?;
+    vm.stack_limit = input.usize()?;
+    let mut costs = [0; 256];
+    for cost in costs.iter_mut() {
+        *cost = input.u64()?;
+    }
+    vm.gas_costs = GasTable::from_costs(costs, input.u64()?);
+    snapshot.scheduler = decode_scheduler(&mut input, snapshot.program.len())?;
+    if !input.0.is_empty() {
+        return Err(PersistError::Corrupt("trailing data"));
+    }
+    vm.restore(&snapshot);
+    Ok(vm)
+}
+
+fn encode_collector(out: &mut Writer, gc: &Collector) {
+    out.bool(gc.enabled);
+    out.usize(gc.threshold);
+    out.usize(gc.allocated_since_collection());
+    out.u64(gc.stats.collections);
+    out.u64(gc.stats.blocks_freed);
+    out.u64(gc.stats.bytes_freed);
+    out.duration(gc.stats.total_time);
+    match gc.stats.last {
+        Some(report) => {
+            out.bool(true);
+            out.usize(report.live_blocks);
+            out.usize(report.freed_blocks);
+            out.usize(report.bytes_freed);
+            out.duration(report.duration);
+        }
+        None => out.bool(false),
+    }
+}
+
+fn decode_collector(input: &mut Reader) -> Result<Collector, PersistError> {
+    let mut gc = Collector::new();
+    gc.enabled = input.bool()?;
+    gc.threshold = input.usize()?;
+    gc.record_allocation(input.usize()?);
+    gc.stats = GcStats {
+        collections: input.u64()?,
+        blocks_freed: input.u64()?,
+        bytes_freed: input.u64()?,
+        total_time: input.duration()?,
+        last: None,
+    };
+    if input.bool()? {
+        gc.stats.last = Some(GcReport {
+            live_blocks: input.usize()?,
+            freed_blocks: input.usize()?,
+            bytes_freed: input.usize()?,
+            duration: input.duration()?,
+        });
+    }
+    Ok(gc)
+}
+
+fn encode_scheduler(out: &mut Writer, scheduler: &Scheduler) {
+    out.u64(scheduler.reductions);
+    out.usize(scheduler.process_limit);
+    out.i32(scheduler.current());
+    out.i32(scheduler.next_pid());
+    out.usize(scheduler.len());
+    for process in scheduler.processes() {
+        out.i32(process.pid);
+        match process.state {
+            ProcessState::Running => out.u8(0),
+            ProcessState::Ready => out.u8(1),
+            ProcessState::Blocked => out.u8(2),
+            ProcessState::Exited(code) => {
+                out.u8(3);
+                out.i32(code);
+            }
+        }
+        out.u64(process.reductions);
+        match &process.context {
+            Some(context) => {
+                out.bool(true);
+                context.registers.iter().for_each(|r| out.i32(*r));
+                context.float_registers.iter().for_each(|r| out.f64(*r));
+                out.usize(context.pcounter);
+                out.u32(context.remainder);
+                out.u8(context.flags.bits());
+                out.usize(context.stack.len());
+                context.stack.iter().for_each(|value| out.i32(*value));
+            }
+            None => out.bool(false),
+        }
+    }
+    let queue: Vec<i32> = scheduler.run_queue().collect();
+    out.usize(queue.len());
+    queue.iter().for_each(|pid| out.i32(*pid));
+}
+
+fn decode_scheduler(input: &mut Reader, program_len: usize) -> Result<Scheduler, PersistError> {
+    let reductions = input.u64()?;
+    let process_limit = input.usize()?;
+    let current = input.i32()?;
+    let next_pid = input.i32()?;
+    let mut processes = BTreeMap::new();
+    for _ in 0..input.len()? {
+        let pid = input.i32()?;
+        let state = match input.u8()? {
+            0 => ProcessState::Running,
+            1 => ProcessState::Ready,
+            2 => ProcessState::Blocked,
+            3 => ProcessState::Exited(input.i32()?),
+            _ => return Err(PersistError::Corrupt("invalid process state")),
+        };
+        let process_reductions = input.u64()?;
+        let context = if input.bool()? {
+            let mut registers = [0; 32];
+            for register in registers.iter_mut() {
+                *register = input.i32()?;
+            }
+            let mut float_registers = [0.0; 32];
+            for register in float_registers.iter_mut() {
+                *register = input.f64()?;
+            }
+            let pcounter = input.usize()?;
+            if pcounter > program_len {
+                return Err(PersistError::Corrupt("program counter past end of program"));
+            }
+            let remainder = input.u32()?;
+            let flags = Flags::from_bits(input.u8()?);
+            let mut stack = vec![];
+            for _ in 0..input.len()? {
+                stack.push(input.i32()?);
+            }
+            Some(Context {
+                registers,
+                float_registers,
+                pcounter,
+                remainder,
+                flags,
+                stack,
+            })
+        } else {
+            None
+        };
+        let process = Process {
+            pid,
+            state,
+            context,
+            reductions: process_reductions,
+        };
+        if pid >= next_pid || processes.insert(pid, process).is_some() {
+            return Err(PersistError::Corrupt("invalid process id"));
+        }
+    }
+    let mut run_queue = VecDeque::new();
+    for _ in 0..input.len()? {
+        let pid = input.i32()?;
+        if pid == current || processes.get(&pid).is_none_or(|process: &Process| process.context.is_none()) {
+            return Err(PersistError::Corrupt("run queue names a process that cannot run"));
+        }
+        run_queue.push_back(pid);
+    }
+    if !processes.contains_key(&current) {
+        return Err(PersistError::Corrupt("no current process"));
+    }
+    let mut scheduler = Scheduler::from_parts(processes, run_queue, current, next_pid);
+    scheduler.reductions = reductions;
+    scheduler.process_limit = process_limit;
+    Ok(scheduler)
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn i32(&mut self, value: i32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+    fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+    fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+    fn duration(&mut self, value: Duration) {
+        self.u64(value.as_secs());
+        self.u32(value.subsec_nanos());
+    }
+    fn option(&mut self, value: Option<u64>) {
+        match value {
+            Some(value) => {
+                self.bool(true);
+                self.u64(value);
+            }
+            None => self.bool(false),
+        }
+    }
+    fn bytes(&mut self, value: &[u8]) {
+        self.usize(value.len());
+        self.0.extend_from_slice(value);
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], PersistError> {
+        if self.0.len() < N {
+            return Err(PersistError::Corrupt("unexpected end of data"));
+        }
+        let (head, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(head.try_into().expect("split_at returned N bytes"))
+    }
+    fn u8(&mut self) -> Result<u8, PersistError> {
+        Ok(self.take::<1>()?[0])
+    }
+    fn bool(&mut self) -> Result<bool, PersistError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(PersistError::Corrupt("invalid boolean")),
+        }
+    }
+    fn u32(&mut self) -> Result<u32, PersistError> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+    fn i32(&mut self) -> Result<i32, PersistError> {
+        Ok(i32::from_be_bytes(self.take()?))
+    }
+    fn u64(&mut self) -> Result<u64, PersistError> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+    fn usize(&mut self) -> Result<usize, PersistError> {
+        usize::try_from(self.u64()?).map_err(|_| PersistError::Corrupt("size does not fit in memory"))
+    }
+    /// A length prefix, checked against the data left so a corrupt length
+    /// cannot trigger a huge allocation.
+    fn len(&mut self) -> Result<usize, PersistError> {
+        let len = self.usize()?;
+        if len > self.0.len() {
+            return Err(PersistError::Corrupt("unexpected end of data"));
+        }
+        Ok(len)
+    }
+    fn f64(&mut self) -> Result<f64, PersistError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+    fn duration(&mut self) -> Result<Duration, PersistError> {
+        let secs = self.u64()?;
+        let nanos = self.u32()?;
+        if nanos >= 1_000_000_000 {
+            return Err(PersistError::Corrupt("invalid duration"));
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+    fn option(&mut self) -> Result<Option<u64>, PersistError> {
+        if self.bool()? {
+            Ok(Some(self.u64()?))
+        } else {
+            Ok(None)
+        }
+    }
+    fn bytes(&mut self) -> Result<Vec<u8>, PersistError> {
+        let len = self.len()?;
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Opcode;
+    use crate::vm::ExitReason;
+
+    fn busy_vm() -> VM {
+        let mut vm = VM::new();
+        vm.registers[0] = 12;
+        vm.float_registers[3] = -2.5;
+        vm.gas = Some(1000);
+        vm.gas_costs.set_cost(Opcode::MUL, 7);
+        vm.stack_limit = 64;
+        vm.gc.enabled = true;
+        // ALOC $0 $1; PUSH $1; ALOC $0 $2; FREE $1; INC $5
+        vm.program = vec![18, 0, 1, 0, 21, 1, 0, 0, 18, 0, 2, 0, 31, 1, 0, 0, 19, 5, 0, 0];
+        for _ in 0..4 {
+            vm.run_once().unwrap();
+        }
+        vm.collect_garbage();
+        vm
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let vm = busy_vm();
+        let mut loaded = decode(&encode(&vm)).unwrap();
+        assert_eq!(loaded.snapshot(), vm.snapshot());
+        assert_eq!(loaded.stack_limit, 64);
+        assert_eq!(loaded.gas_costs, vm.gas_costs);
+        assert_eq!(loaded.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(loaded.registers[5], 1);
+    }
+
+    #[test]
+    fn test_rejects_damaged_files() {
+        let bytes = encode(&busy_vm());
+        let mut flipped = bytes.clone();
+        flipped[40] ^= 1;
+        assert!(matches!(decode(&flipped), Err(PersistError::ChecksumMismatch)));
+        assert!(matches!(decode(&bytes[..bytes.len() - 1]), Err(PersistError::ChecksumMismatch)));
+        assert!(matches!(decode(b"nope"), Err(PersistError::NotAVmFile)));
+        let mut future = bytes.clone();
+        future[5] = 2;
+        assert!(matches!(decode(&future), Err(PersistError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn test_round_trip_processes() {
+        let mut vm = busy_vm();
+        vm.scheduler.reductions = 3;
+        vm.scheduler.spawn(16, 9).unwrap();
+        vm.run_processes().unwrap();
+        vm.scheduler.spawn(4, 1).unwrap();
+        let loaded = decode(&encode(&vm)).unwrap();
+        assert_eq!(loaded.snapshot(), vm.snapshot());
+        assert_eq!(loaded.scheduler.reductions, 3);
+    }
+
+    #[test]
+    fn test_rejects_inconsistent_state() {
+        let mut vm = VM::new();
+        vm.program = vec![5];
+        vm.pcounter = 1;
+        let mut bytes = encode(&vm);
+        // Point the program counter past the end and fix up the checksum.
+        let pcounter = 6 + 32 * 4 + 32 * 8 + 7;
+        bytes[pcounter] = 2;
+        let body = bytes.len() - 4;
+        let checksum = crc32(&bytes[..body]);
+        bytes[body..].copy_from_slice(&checksum.to_be_bytes());
+        assert!(matches!(decode(&bytes), Err(PersistError::Corrupt(_))));
+    }
+}
@@ -1,25 +1,40 @@
 pub mod parser;
 
 use std::{
+    collections::VecDeque,
     io::{self, Write, Read},
     num::ParseIntError,
 };
 
 use nom::types::CompleteStr;
 
-use crate::vm::VM;
+use crate::history::History;
+use crate::net::NetPolicy;
+use crate::vm::{ExitReason, VmTrap, VM};
 use crate::repl::parser::Parser;
 use crate::asm::program_parser::parse_program;
 
 pub static BANNER: &str = "Hello welcome to the incomplete lang REPL owo";
 pub static PROMPT: &str = ">>> ";
 pub static PREFIX: &str = ".";
+/// How many instructions `.undo` can step back over.
+pub const UNDO_LIMIT: usize = 100;
+
+/// What `.undo` needs to take back one line: the program is cut back to
+/// `program_len` and, if the line's instruction ran, the VM's history
+/// steps back over it.
+#[derive(Debug, Copy, Clone)]
+struct UndoEntry {
+    program_len: usize,
+    executed: bool,
+}
 
 #[derive(Default)]
 pub struct REPL {
     vm: VM,
     buffer: Vec<String>,
     hex_mode: bool,
+    undo: VecDeque<UndoEntry>,
 }
 
 impl REPL {
@@ -28,6 +43,7 @@ impl REPL {
             vm,
             buffer: Vec::new(),
             hex_mode: false,
+            undo: VecDeque::new(),
         }
     }
     pub fn message(&mut self, msg: String) {
@@ -66,8 +82,13 @@ impl REPL {
             ".clear_registers" => self.clear_registers(&args[1..]),
             ".registers" => self.registers(&args[1..]),
             ".register" => self.register(&args[1..]),
+            ".fregisters" => self.float_registers(&args[1..]),
             ".load_file" => self.load_file(&args[1..]),
             ".hex_mode" => self.hex_mode(&args[1..]),
+            ".undo" => self.undo(&args[1..]),
+            ".sandbox" => self.sandbox(&args[1..]),
+            ".network" => self.network(&args[1..]),
+            ".processes" => self.processes(&args[1..]),
             _ => {
                 self.message("Invalid command!".to_string());
             }
@@ -98,13 +119,18 @@ impl REPL {
 
     fn clear_program(&mut self, _args: &[&str]) {
         self.vm.program.clear();
+        self.forget_undo();
     }
 
     fn clear_registers(&mut self, _args: &[&str]) {
         self.message("Setting all registers to 0".to_string());
+        self.forget_undo();
         for i in 0..self.vm.registers.len() {
             self.vm.registers[i] = 0;
         }
+        for i in 0..self.vm.float_registers.len() {
+            self.vm.float_registers[i] = 0.0;
+        }
         self.message("Done!".to_string());
     }
 
@@ -118,6 +144,16 @@ impl REPL {
         self.message("End of Register Listing".to_string());
     }
 
+    fn float_registers(&mut self, _args: &[&str]) {
+        self.message("Listing floating point registers and all contents:".to_string());
+        let mut results = vec![];
+        for register in &self.vm.float_registers {
+            results.push(*register);
+        }
+        self.message(format!("{:#?}", results));
+        self.message("End of Floating Point Register Listing".to_string());
+    }
+
     fn register(&mut self, args: &[&str]) {
         if args.len() != 1 {
             self.message(format!("Register 0 contains the value {}", self.vm.registers[0]));
@@ -148,8 +184,10 @@ impl REPL {
             }
         };
         self.vm.program.append(&mut parsed_program.to_bytes());
+        self.forget_undo();
         println!("Loaded program from file {}", file_name);
-        self.vm.run();
+        let result = self.vm.run();
+        self.report(result);
     }
 
     fn hex_mode(&mut self, args: &[&str]) {
@@ -162,6 +200,117 @@ impl REPL {
         }
     }
 
+    fn undo(&mut self, _args: &[&str]) {
+        match self.undo.pop_back() {
+            Some(entry) => {
+                // History is dropped at context switches and trimmed when it
+                // grows too large, so older lines may be out of reach.
+                if entry.executed && !self.vm.step_back() {
+                    self.undo.clear();
+                    self.message("Nothing to undo".to_string());
+                    return;
+                }
+                self.vm.program.truncate(entry.program_len);
+                self.message("Undid last instruction".to_string());
+            }
+            None => self.message("Nothing to undo".to_string()),
+        }
+    }
+
+    fn sandbox(&mut self, args: &[&str]) {
+        match args {
+            [] => match self.vm.files.root() {
+                Some(root) => self.message(format!("Files are confined to {}", root.display())),
+                None => self.message("File access is disabled".to_string()),
+            },
+            ["off"] => {
+                self.vm.files.clear_root();
+                self.message("File access is disabled".to_string());
+            }
+            [dir] => match self.vm.files.set_root(dir) {
+                Ok(()) => self.message(format!("Files are confined to {}", dir)),
+                Err(e) => self.message(format!("Unable to use {} as the sandbox: {}", dir, e)),
+            },
+            _ => self.message("Invalid number of arguments".to_string()),
+        }
+    }
+
+    fn network(&mut self, args: &[&str]) {
+        let policy = match args {
+            [] => self.vm.sockets.policy,
+            ["off"] => NetPolicy::Disabled,
+            ["loopback"] => NetPolicy::LoopbackOnly,
+            ["any"] => NetPolicy::Unrestricted,
+            _ => {
+                self.message("Expected off, loopback or any".to_string());
+                return;
+            }
+        };
+        self.vm.sockets.policy = policy;
+        match policy {
+            NetPolicy::Disabled => self.message("Network access is disabled".to_string()),
+            NetPolicy::LoopbackOnly => self.message("Sockets are restricted to loopback".to_string()),
+            NetPolicy::Unrestricted => self.message("Sockets may use any address".to_string()),
+        }
+    }
+
+    fn processes(&mut self, _args: &[&str]) {
+        self.message("Listing processes:".to_string());
+        let current = self.vm.scheduler.current();
+        let lines: Vec<String> = self
+            .vm
+            .scheduler
+            .processes()
+            .map(|process| {
+                let pcounter = match &process.context {
+                    Some(context) => context.pcounter,
+                    None if process.pid == current => self.vm.pcounter,
+                    None => 0,
+                };
+                format!(
+                    "{:>4} {:?} at {} after {} reductions",
+                    process.pid, process.state, pcounter, process.reductions
+                )
+            })
+            .collect();
+        for line in lines {
+            self.message(line);
+        }
+        self.message("End of Process Listing".to_string());
+    }
+
+    /// Drops everything `.undo` could take back, for commands that change
+    /// the VM behind the recorded lines' backs.
+    fn forget_undo(&mut self) {
+        self.undo.clear();
+        if let Some(history) = self.vm.history.as_mut() {
+            history.clear();
+        }
+    }
+
+    fn save_undo(&mut self) {
+        self.vm.history.get_or_insert_with(History::new);
+        if self.undo.len() == UNDO_LIMIT {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(UndoEntry {
+            program_len: self.vm.program.len(),
+            executed: false,
+        });
+    }
+
+    fn report(&mut self, result: Result<ExitReason, VmTrap>) {
+        match result {
+            Ok(ExitReason::Halted) => self.message("HLT encountered".to_string()),
+            Ok(ExitReason::OutOfFuel) => self.message("Out of fuel".to_string()),
+            Ok(ExitReason::Paused) => self.message("Paused".to_string()),
+            Ok(ExitReason::Blocked) => self.message("Waiting on a socket".to_string()),
+            Ok(ExitReason::Exited) => self.message("Process exited".to_string()),
+            Ok(_) => {}
+            Err(trap) => self.message(format!("Trap: {}", trap)),
+        }
+    }
+
     pub fn run(&mut self) {
         self.message(BANNER.to_string());
         self.prompt();
@@ -175,6 +324,7 @@ impl REPL {
                 self.execute(&buffer);
                 self.prompt();
             } else {
+                self.save_undo();
                 if self.hex_mode{
                         let program = self.parse_hex(CompleteStr(&buffer).trim());
                         match program {
@@ -184,7 +334,9 @@ impl REPL {
                             }
                         }
                         Err(_e) => {
-                            println!("Unable to decode hex string. Please enter 4 groups of 2 hex characters.")
+                            println!("Unable to decode hex string. Please enter 4 groups of 2 hex characters.");
+                            self.undo.pop_back();
+                            continue;
                         }
                     }
                 } else {
@@ -193,12 +345,24 @@ impl REPL {
                       Ok((_rest,  program)) => program,
                       Err(_) => {
                           self.message("Unable to parse instruction.".to_string());
+                          self.undo.pop_back();
                           continue;
                       }
                   };
                   self.vm.program.append(&mut program.to_bytes());
                 }
-                self.vm.run_once();
+                let result = self.vm.run_once();
+                // Mirrors which results `run_once` keeps in the history. A
+                // line that trapped is taken back out, or every later line
+                // would run into it again.
+                if result.is_err() {
+                    if let Some(entry) = self.undo.pop_back() {
+                        self.vm.program.truncate(entry.program_len);
+                    }
+                } else if let Some(entry) = self.undo.back_mut() {
+                    entry.executed = !matches!(result, Ok(ExitReason::EndOfProgram) | Ok(ExitReason::Blocked));
+                }
+                self.report(result);
                 self.prompt();
             }
         }
@@ -0,0 +1,291 @@
+//! Lightweight processes sharing one VM.
+//!
+//! Every process has its own registers, flags, stack and program counter
+//! but shares the program, heap and open files and sockets with the
+//! others. Only the running process lives in the VM's fields; the rest
+//! are parked here as a `Context` until `VM::run_processes` switches to
+//! them.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::flags::Flags;
+
+pub type Pid = i32;
+
+/// The process a fresh VM starts out running.
+pub const MAIN_PROCESS: Pid = 0;
+/// Instructions a process may run before it is preempted.
+pub const DEFAULT_REDUCTIONS: u64 = 1000;
+/// Upper bound on the number of processes that have not exited.
+pub const DEFAULT_PROCESS_LIMIT: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ProcessState {
+    Running,
+    /// Waiting in the run queue.
+    Ready,
+    /// Its last instruction would have blocked; it is retried on its next
+    /// turn.
+    Blocked,
+    /// Finished with `EXIT` or by running off the end of the program.
+    Exited(i32),
+}
+
+/// The registers and stack of a process that is not running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Context {
+    pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
+    pub pcounter: usize,
+    pub remainder: u32,
+    pub flags: Flags,
+    pub stack: Vec<i32>,
+}
+
+impl Context {
+    /// A process starting at `pcounter` with `argument` in `$0`.
+    pub fn new(pcounter: usize, argument: i32) -> Self {
+        let mut registers = [0; 32];
+        registers[0] = argument;
+        Context {
+            registers,
+            float_registers: [0.0; 32],
+            pcounter,
+            remainder: 0,
+            flags: Flags::default(),
+            stack: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Process {
+    pub pid: Pid,
+    pub state: ProcessState,
+    /// `None` while the process runs, since its state is in the VM, and
+    /// once it has exited.
+    pub context: Option<Context>,
+    /// Instructions executed so far.
+    pub reductions: u64,
+}
+
+/// The process table and run queue.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheduler {
+    pub reductions: u64,
+    pub process_limit: usize,
+    processes: BTreeMap<Pid, Process>,
+    run_queue: VecDeque<Pid>,
+    current: Pid,
+    next_pid: Pid,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    /// A table holding only the running main process.
+    pub fn new() -> Self {
+        let mut processes = BTreeMap::new();
+        processes.insert(
+            MAIN_PROCESS,
+            Process {
+                pid: MAIN_PROCESS,
+                state: ProcessState::Running,
+                context: None,
+                reductions: 0,
+            },
+        );
+        Scheduler::from_parts(processes, VecDeque::new(), MAIN_PROCESS, MAIN_PROCESS + 1)
+    }
+
+    /// Rebuilds a scheduler from what `processes`, `run_queue`, `current`
+    /// and `next_pid` returned, with the default limits.
+    pub fn from_parts(
+        processes: BTreeMap<Pid, Process>,
+        run_queue: VecDeque<Pid>,
+        current: Pid,
+        next_pid: Pid,
+    ) -> Self {
+        Scheduler {
+            reductions: DEFAULT_REDUCTIONS,
+            process_limit: DEFAULT_PROCESS_LIMIT,
+            processes,
+            run_queue,
+            current,
+            next_pid,
+        }
+    }
+
+    /// The process whose state is in the VM.
+    pub fn current(&self) -> Pid {
+        self.current
+    }
+
+    pub fn next_pid(&self) -> Pid {
+        self.next_pid
+    }
+
+    pub fn process(&self, pid: Pid) -> Option<&Process> {
+        self.processes.get(&pid)
+    }
+
+    pub fn processes(&self) -> impl Iterator<Item = &Process> {
+        self.processes.values()
+    }
+
+    /// Processes waiting for a turn, in the order they will get it.
+    pub fn run_queue(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.run_queue.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.processes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty()
+    }
+
+    /// Processes that have not exited.
+    pub fn live(&self) -> usize {
+        self.processes
+            .values()
+            .filter(|process| !matches!(process.state, ProcessState::Exited(_)))
+            .count()
+    }
+
+    /// Adds a ready process, or returns `None` at the process limit.
+    /// Exited processes are forgotten first if the table is full of them.
+    pub fn spawn(&mut self, pcounter: usize, argument: i32) -> Option<Pid> {
+        if self.processes.len() >= self.process_limit {
+            self.remove_exited();
+        }
+        if self.processes.len() >= self.process_limit {
+            return None;
+        }
+        let pid = self.next_pid;
+        self.next_pid = self.next_pid.checked_add(1)?;
+        self.processes.insert(
+            pid,
+            Process {
+                pid,
+                state: ProcessState::Ready,
+                context: Some(Context::new(pcounter, argument)),
+                reductions: 0,
+            },
+        );
+        self.run_queue.push_back(pid);
+        Some(pid)
+    }
+
+    /// Marks the running process as finished.
+    pub fn exit(&mut self, code: i32) {
+        self.set_state(self.current, ProcessState::Exited(code));
+    }
+
+    pub(crate) fn set_state(&mut self, pid: Pid, state: ProcessState) {
+        if let Some(process) = self.processes.get_mut(&pid) {
+            process.state = state;
+        }
+    }
+
+    pub(crate) fn charge(&mut self, reductions: u64) {
+        if let Some(process) = self.processes.get_mut(&self.current) {
+            process.reductions += reductions;
+        }
+    }
+
+    /// Parks the running process with `context` and makes the next one in
+    /// the queue current, returning its context. A process that has not
+    /// exited goes to the back of the queue. Returns `None`, leaving the
+    /// current process running, when the queue is empty.
+    pub(crate) fn switch(&mut self, context: Context) -> Option<Context> {
+        let next = self.run_queue.pop_front()?;
+        let current = self.current;
+        if let Some(process) = self.processes.get_mut(&current) {
+            if !matches!(process.state, ProcessState::Exited(_)) {
+                if process.state == ProcessState::Running {
+                    process.state = ProcessState::Ready;
+                }
+                process.context = Some(context);
+                self.run_queue.push_back(current);
+            }
+        }
+        self.current = next;
+        let process = self.processes.get_mut(&next).expect("queued process exists");
+        process.state = ProcessState::Running;
+        process.context.take()
+    }
+
+    /// Forgets processes that have exited, except the running one.
+    pub fn remove_exited(&mut self) {
+        let current = self.current;
+        self.processes
+            .retain(|pid, process| *pid == current || !matches!(process.state, ProcessState::Exited(_)));
+        let processes = &self.processes;
+        self.run_queue.retain(|pid| processes.contains_key(pid));
+    }
+
+    /// Registers and stack slots of parked processes, which the garbage
+    /// collector must treat as roots.
+    pub fn roots(&self) -> impl Iterator<Item = i32> + '_ {
+        self.processes
+            .values()
+            .filter_map(|process| process.context.as_ref())
+            .flat_map(|context| context.registers.iter().chain(context.stack.iter()).copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_and_switch() {
+        let mut scheduler = Scheduler::new();
+        scheduler.process_limit = 3;
+        assert_eq!(scheduler.spawn(8, 42), Some(1));
+        assert_eq!(scheduler.spawn(12, 0), Some(2));
+        assert_eq!(scheduler.spawn(16, 0), None);
+        assert_eq!(scheduler.live(), 3);
+
+        let next = scheduler.switch(Context::new(4, 7)).unwrap();
+        assert_eq!(scheduler.current(), 1);
+        assert_eq!((next.pcounter, next.registers[0]), (8, 42));
+        assert_eq!(scheduler.run_queue().collect::<Vec<_>>(), vec![2, 0]);
+        assert_eq!(scheduler.process(0).unwrap().state, ProcessState::Ready);
+        assert_eq!(scheduler.roots().filter(|root| *root == 7).count(), 1);
+
+        scheduler.exit(3);
+        scheduler.switch(Context::new(0, 0)).unwrap();
+        assert_eq!(scheduler.run_queue().collect::<Vec<_>>(), vec![0]);
+        assert_eq!(scheduler.process(1).unwrap().state, ProcessState::Exited(3));
+        assert_eq!(scheduler.live(), 2);
+        scheduler.remove_exited();
+        assert_eq!(scheduler.len(), 2);
+    }
+
+    #[test]
+    fn test_spawn_reaps_exited_processes() {
+        let mut scheduler = Scheduler::new();
+        scheduler.process_limit = 2;
+        assert_eq!(scheduler.spawn(8, 0), Some(1));
+        scheduler.set_state(1, ProcessState::Exited(0));
+        assert_eq!(scheduler.spawn(8, 0), Some(2));
+        assert!(scheduler.process(1).is_none());
+        assert_eq!(scheduler.run_queue().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(scheduler.spawn(8, 0), None);
+    }
+
+    #[test]
+    fn test_switch_with_empty_queue() {
+        let mut scheduler = Scheduler::new();
+        assert_eq!(scheduler.switch(Context::new(0, 0)), None);
+        assert_eq!(scheduler.current(), MAIN_PROCESS);
+        assert_eq!(scheduler.process(MAIN_PROCESS).unwrap().state, ProcessState::Running);
+    }
+}
@@ -0,0 +1,28 @@
+use crate::allocator::Allocator;
+use crate::flags::Flags;
+use crate::gc::Collector;
+use crate::scheduler::Scheduler;
+
+/// Everything a running program can observe or change, captured by
+/// `VM::snapshot` and put back by `VM::restore`.
+///
+/// Host-side configuration is not part of a snapshot: the heap and stack
+/// limits, the gas cost table and attached observers stay as they are
+/// when restoring.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
+    pub pcounter: usize,
+    pub program: Vec<u8>,
+    pub remainder: u32,
+    pub flags: Flags,
+    pub heap: Vec<u8>,
+    pub allocator: Allocator,
+    pub gc: Collector,
+    pub stack: Vec<i32>,
+    pub fuel: Option<u64>,
+    pub gas: Option<u64>,
+    /// The parked processes; the running one is in the fields above.
+    pub scheduler: Scheduler,
+}
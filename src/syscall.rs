@@ -0,0 +1,134 @@
+//! Host functions callable from bytecode with `SYSCALL #n`.
+//!
+//! Arguments are passed in `$1` to `$6` and the host function's return
+//! value is written to `$0`. A function may also read and write any
+//! register or heap byte through its `SyscallContext`; if it then fails,
+//! those writes are undone along with the rest of the trapping `SYSCALL`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Register that receives a host function's return value.
+pub const RETURN_REGISTER: usize = 0;
+/// Registers `$1` through `$6` carry arguments.
+pub const MAX_ARGUMENTS: usize = 6;
+
+/// Why a host function failed. Either way the `SYSCALL` traps.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SyscallError {
+    /// A heap range passed to the host function is out of bounds.
+    BadAddress { address: i64 },
+    Failed(String),
+}
+
+impl From<String> for SyscallError {
+    fn from(message: String) -> Self {
+        SyscallError::Failed(message)
+    }
+}
+
+impl From<&str> for SyscallError {
+    fn from(message: &str) -> Self {
+        SyscallError::Failed(message.to_string())
+    }
+}
+
+/// The VM state a host function may touch while it runs.
+pub struct SyscallContext<'a> {
+    pub registers: &'a mut [i32; 32],
+    pub float_registers: &'a mut [f64; 32],
+    /// The heap cannot be resized from here; programs allocate with `ALOC`.
+    pub heap: &'a mut [u8],
+}
+
+impl SyscallContext<'_> {
+    /// The `index`th argument, counting from 0 for `$1`.
+    pub fn arg(&self, index: usize) -> i32 {
+        assert!(index < MAX_ARGUMENTS, "syscalls take at most {} arguments", MAX_ARGUMENTS);
+        self.registers[1 + index]
+    }
+
+    /// Borrows `len` heap bytes starting at `address`.
+    pub fn read(&self, address: i32, len: i32) -> Result<&[u8], SyscallError> {
+        let range = heap_range(self.heap.len(), address, len)?;
+        Ok(&self.heap[range])
+    }
+
+    pub fn write(&mut self, address: i32, bytes: &[u8]) -> Result<(), SyscallError> {
+        let range = heap_range(self.heap.len(), address, bytes.len() as i32)?;
+        self.heap[range].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+fn heap_range(heap_len: usize, address: i32, len: i32) -> Result<std::ops::Range<usize>, SyscallError> {
+    if address < 0 || len < 0 || address as usize + len as usize > heap_len {
+        return Err(SyscallError::BadAddress { address: address as i64 });
+    }
+    Ok(address as usize..address as usize + len as usize)
+}
+
+pub type HostFunction = Box<dyn FnMut(&mut SyscallContext) -> Result<i32, SyscallError>>;
+
+/// Host functions registered on a VM, by syscall number.
+#[derive(Default)]
+pub struct SyscallTable {
+    functions: BTreeMap<u16, HostFunction>,
+}
+
+impl SyscallTable {
+    pub fn new() -> Self {
+        SyscallTable {
+            functions: BTreeMap::new(),
+        }
+    }
+
+    /// Registers `function` as syscall `number`, replacing any previous one.
+    pub fn register<F>(&mut self, number: u16, function: F)
+    where
+        F: FnMut(&mut SyscallContext) -> Result<i32, SyscallError> + 'static,
+    {
+        self.functions.insert(number, Box::new(function));
+    }
+
+    pub fn unregister(&mut self, number: u16) -> bool {
+        self.functions.remove(&number).is_some()
+    }
+
+    pub fn get_mut(&mut self, number: u16) -> Option<&mut HostFunction> {
+        self.functions.get_mut(&number)
+    }
+
+    pub fn numbers(&self) -> impl Iterator<Item = u16> + '_ {
+        self.functions.keys().copied()
+    }
+}
+
+impl fmt::Debug for SyscallTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_heap_access() {
+        let mut registers = [0; 32];
+        let mut float_registers = [0.0; 32];
+        let mut heap = vec![0; 8];
+        registers[1] = 4;
+        let mut context = SyscallContext {
+            registers: &mut registers,
+            float_registers: &mut float_registers,
+            heap: &mut heap,
+        };
+        assert_eq!(context.arg(0), 4);
+        context.write(context.arg(0), &[1, 2]).unwrap();
+        assert_eq!(context.read(4, 3), Ok(&[1, 2, 0][..]));
+        assert_eq!(context.read(6, 3), Err(SyscallError::BadAddress { address: 6 }));
+        assert_eq!(context.write(-1, &[1]), Err(SyscallError::BadAddress { address: -1 }));
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+
+/// Why a call to `VM::run` or `VM::run_once` returned without a trap.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ExitReason {
+    /// A `HLT` instruction was executed.
+    Halted,
+    /// The program counter reached the end of the program.
+    EndOfProgram,
+    /// A single instruction was executed and the VM can keep going.
+    Stepped,
+    /// The step budget in `VM::fuel` ran out. The VM is left at the next
+    /// instruction, so refuelling and calling `run` again resumes it.
+    OutOfFuel,
+    /// An observer, such as a watchpoint, asked to stop after the
+    /// instruction that just ran. Calling `run` again carries on.
+    Paused,
+    /// A socket instruction would have had to wait. The VM is left at that
+    /// instruction, so calling `run` again retries it.
+    Blocked,
+    /// `YIELD` gave up the rest of the process's turn. `run` carries on,
+    /// since a lone VM has nothing else to switch to.
+    Yielded,
+    /// The running process finished with `EXIT`. `VM::run_processes`
+    /// returns it once every process has finished.
+    Exited,
+}
+
+/// A fault raised while executing an instruction.
+///
+/// `pc` is always the address of the instruction that faulted; for
+/// `PcOutOfBounds` that is the instruction which read or jumped past the
+/// end of the program.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum VmTrap {
+    IllegalOpcode { pc: usize, byte: u8 },
+    DivideByZero { pc: usize },
+    BadRegister { pc: usize, register: u8 },
+    PcOutOfBounds { pc: usize },
+    HeapOverflow { pc: usize, requested: i64 },
+    StackOverflow { pc: usize },
+    StackUnderflow { pc: usize },
+    HeapOutOfBounds { pc: usize, address: i64 },
+    InvalidHeapAddress { pc: usize, address: i64 },
+    ArithmeticOverflow { pc: usize },
+    OutOfGas { pc: usize, required: u64, remaining: u64 },
+    UnknownSyscall { pc: usize, number: u16 },
+    SyscallFailed { pc: usize, number: u16, message: String },
+    /// `PRTC` was given a value that is not a Unicode scalar value.
+    InvalidChar { pc: usize, value: i32 },
+    /// The VM's output or input failed.
+    Io { pc: usize, message: String },
+    /// `READI` read a line that is not an integer.
+    InvalidInput { pc: usize, input: String },
+    /// A file path leads outside the sandbox root, or there is no root.
+    PathEscape { pc: usize, path: String },
+    BadFileDescriptor { pc: usize, fd: i32 },
+    /// The network policy does not allow this address.
+    AddressDenied { pc: usize, address: String },
+    /// `SPAWN` hit the scheduler's process limit.
+    TooManyProcesses { pc: usize },
+}
+
+impl VmTrap {
+    pub fn pc(&self) -> usize {
+        match *self {
+            VmTrap::IllegalOpcode { pc, .. }
+            | VmTrap::DivideByZero { pc }
+            | VmTrap::BadRegister { pc, .. }
+            | VmTrap::PcOutOfBounds { pc }
+            | VmTrap::HeapOverflow { pc, .. }
+            | VmTrap::StackOverflow { pc }
+            | VmTrap::StackUnderflow { pc }
+            | VmTrap::HeapOutOfBounds { pc, .. }
+            | VmTrap::InvalidHeapAddress { pc, .. }
+            | VmTrap::ArithmeticOverflow { pc }
+            | VmTrap::OutOfGas { pc, .. }
+            | VmTrap::UnknownSyscall { pc, .. }
+            | VmTrap::SyscallFailed { pc, .. }
+            | VmTrap::InvalidChar { pc, .. }
+            | VmTrap::Io { pc, .. }
+            | VmTrap::InvalidInput { pc, .. }
+            | VmTrap::PathEscape { pc, .. }
+            | VmTrap::BadFileDescriptor { pc, .. }
+            | VmTrap::AddressDenied { pc, .. }
+            | VmTrap::TooManyProcesses { pc } => pc,
+        }
+    }
+}
+
+impl fmt::Display for VmTrap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmTrap::IllegalOpcode { pc, byte } => {
+                write!(f, "illegal opcode {} at {}", byte, pc)
+            }
+            VmTrap::DivideByZero { pc } => write!(f, "divide by zero at {}", pc),
+            VmTrap::BadRegister { pc, register } => {
+                write!(f, "bad register {} at {}", register, pc)
+            }
+            VmTrap::PcOutOfBounds { pc } => write!(f, "program counter out of bounds at {}", pc),
+            VmTrap::HeapOverflow { pc, requested } => {
+                write!(f, "heap overflow at {} (requested {} bytes)", pc, requested)
+            }
+            VmTrap::StackOverflow { pc } => write!(f, "stack overflow at {}", pc),
+            VmTrap::StackUnderflow { pc } => write!(f, "stack underflow at {}", pc),
+            VmTrap::HeapOutOfBounds { pc, address } => {
+                write!(f, "heap access out of bounds at {} (address {})", pc, address)
+            }
+            VmTrap::InvalidHeapAddress { pc, address } => {
+                write!(f, "{} is not an allocated block at {}", address, pc)
+            }
+            VmTrap::ArithmeticOverflow { pc } => write!(f, "arithmetic overflow at {}", pc),
+            VmTrap::OutOfGas { pc, required, remaining } => {
+                write!(f, "out of gas at {} (needed {}, {} left)", pc, required, remaining)
+            }
+            VmTrap::UnknownSyscall { pc, number } => write!(f, "unknown syscall {} at {}", number, pc),
+            VmTrap::SyscallFailed { pc, number, message } => {
+                write!(f, "syscall {} failed at {}: {}", number, pc, message)
+            }
+            VmTrap::InvalidChar { pc, value } => write!(f, "invalid character {} at {}", value, pc),
+            VmTrap::Io { pc, message } => write!(f, "i/o error at {}: {}", pc, message),
+            VmTrap::InvalidInput { pc, input } => write!(f, "expected an integer at {}, read {:?}", pc, input),
+            VmTrap::PathEscape { pc, path } => write!(f, "path {:?} is outside the sandbox at {}", path, pc),
+            VmTrap::BadFileDescriptor { pc, fd } => write!(f, "bad file descriptor {} at {}", fd, pc),
+            VmTrap::AddressDenied { pc, address } => write!(f, "network access to {} denied at {}", address, pc),
+            VmTrap::TooManyProcesses { pc } => write!(f, "too many processes at {}", pc),
+        }
+    }
+}
+
+impl std::error::Error for VmTrap {}
@@ -1,53 +1,789 @@
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::allocator::{AllocError, Allocator, HeapStats};
+use crate::console::{Input, InputSource, Output};
+use crate::files::{FileError, FileTable};
+use crate::flags::Flags;
+use crate::net::{NetError, SocketTable};
+use crate::scheduler::{Context, ProcessState, Scheduler};
+use crate::gas::GasTable;
+use crate::history::{Delta, History, Undo};
+use crate::persist::{self, PersistError};
+use crate::snapshot::Snapshot;
+use crate::syscall::{SyscallContext, SyscallError, SyscallTable, RETURN_REGISTER};
+use crate::observer::{DecodedInstruction, ObserverList, VmObserver};
+use crate::gc::{Collector, GcReport};
 use crate::instructions::Opcode;
-#[derive(Debug, Default)]
+pub use crate::trap::{ExitReason, VmTrap};
+
+/// Upper bound on the heap size `ALOC` may grow to, in bytes.
+pub const DEFAULT_HEAP_LIMIT: usize = 16 * 1024 * 1024;
+/// Maximum number of values the call stack may hold.
+pub const DEFAULT_STACK_LIMIT: usize = 1024;
+
+#[derive(Debug)]
 pub struct VM {
     pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
     pub pcounter: usize,
     pub program: Vec<u8>,
     pub remainder: u32,
-    pub is_equal: bool,
-    pub is_greater: bool,
+    pub flags: Flags,
     pub heap: Vec<u8>,
+    pub heap_limit: usize,
+    pub allocator: Allocator,
+    pub gc: Collector,
+    /// Values pushed by `PUSH` and return addresses pushed by `CALL`.
+    pub stack: Vec<i32>,
+    pub stack_limit: usize,
+    /// Instructions `run` may still execute before it returns `OutOfFuel`.
+    /// `None` means no limit.
+    pub fuel: Option<u64>,
+    /// Gas left for the program, charged according to `gas_costs`. `None`
+    /// turns metering off.
+    pub gas: Option<u64>,
+    pub gas_costs: GasTable,
+    /// Notified of every instruction, write, halt and trap. When empty the
+    /// VM skips all the bookkeeping.
+    pub observers: ObserverList,
+    /// Where the print instructions write to.
+    pub output: Output,
+    /// Where the read instructions read from.
+    pub input: Input,
+    /// Files opened by the program, confined to the table's root.
+    pub files: FileTable,
+    /// Sockets opened by the program, restricted by the table's policy.
+    pub sockets: SocketTable,
+    /// The other processes sharing this VM; see `run_processes`.
+    pub scheduler: Scheduler,
+    /// Host functions reachable through `SYSCALL`.
+    pub syscalls: SyscallTable,
+    /// Set to record every instruction so it can be stepped back over.
+    pub history: Option<History>,
+    /// Address of the instruction currently being executed, used to report traps.
+    instruction_start: usize,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
         VM {
             registers: [0; 32],
+            float_registers: [0.0; 32],
             pcounter: 0,
             program: vec![],
             remainder: 0,
-            is_equal: false,
-            is_greater: false,
+            flags: Flags::default(),
             heap: vec![],
+            heap_limit: DEFAULT_HEAP_LIMIT,
+            allocator: Allocator::new(),
+            gc: Collector::new(),
+            stack: vec![],
+            stack_limit: DEFAULT_STACK_LIMIT,
+            fuel: None,
+            gas: None,
+            gas_costs: GasTable::new(),
+            observers: ObserverList::new(),
+            output: Output::stdout(),
+            input: Input::stdin(),
+            files: FileTable::new(),
+            sockets: SocketTable::new(),
+            scheduler: Scheduler::new(),
+            syscalls: SyscallTable::new(),
+            history: None,
+            instruction_start: 0,
         }
     }
-    fn get_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pcounter]);
-        self.pcounter += 1;
-        opcode
+    fn get_opcode(&mut self) -> Result<Opcode, VmTrap> {
+        Ok(Opcode::from(self.next_8_bits()?))
     }
-    pub fn run(&mut self) {
-        let mut is_done = false;
-        while !is_done {
-            is_done = self.execute_instruction();
+    pub fn run(&mut self) -> Result<ExitReason, VmTrap> {
+        loop {
+            match self.step()? {
+                ExitReason::Stepped | ExitReason::Yielded => continue,
+                reason => return Ok(reason),
+            }
         }
     }
-    pub fn run_once(&mut self) -> bool {
-        self.execute_instruction()
+    /// Like `run_once`, but spends one unit of `fuel` and returns
+    /// `OutOfFuel` instead of executing anything once it is used up.
+    pub fn step(&mut self) -> Result<ExitReason, VmTrap> {
+        if self.fuel == Some(0) {
+            return Ok(ExitReason::OutOfFuel);
+        }
+        let reason = self.run_once()?;
+        if reason != ExitReason::EndOfProgram && reason != ExitReason::Blocked {
+            if let Some(fuel) = self.fuel.as_mut() {
+                *fuel -= 1;
+            }
+        }
+        Ok(reason)
     }
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pcounter];
-        self.pcounter += 1;
+    /// Runs every process round robin until they have all exited, giving
+    /// each up to `scheduler.reductions` instructions per turn.
+    ///
+    /// Returns `Exited` once no process is left and `Blocked` when every
+    /// live process is waiting on a socket. Like `run` it stops early on
+    /// `HLT`, traps, running out of fuel and pauses, leaving the offending
+    /// process current. Recorded history is dropped at each context switch,
+    /// so reverse stepping never crosses one.
+    pub fn run_processes(&mut self) -> Result<ExitReason, VmTrap> {
+        let mut blocked = 0;
+        loop {
+            if self.scheduler.live() == 0 {
+                return Ok(ExitReason::Exited);
+            }
+            let current = self.scheduler.current();
+            let state = self.scheduler.process(current).map(|process| process.state);
+            if !matches!(state, Some(ProcessState::Exited(_))) {
+                self.scheduler.set_state(current, ProcessState::Running);
+                match self.run_turn()? {
+                    ExitReason::Yielded | ExitReason::Exited => blocked = 0,
+                    ExitReason::EndOfProgram => {
+                        self.scheduler.exit(0);
+                        blocked = 0;
+                    }
+                    ExitReason::Blocked => {
+                        self.scheduler.set_state(current, ProcessState::Blocked);
+                        blocked += 1;
+                        if blocked >= self.scheduler.live() {
+                            return Ok(ExitReason::Blocked);
+                        }
+                    }
+                    reason => return Ok(reason),
+                }
+            }
+            self.switch_process();
+        }
+    }
+
+    /// Runs the current process until it stops by itself or is preempted,
+    /// which is reported as `Yielded`.
+    fn run_turn(&mut self) -> Result<ExitReason, VmTrap> {
+        let mut executed = 0;
+        let result = loop {
+            if executed >= self.scheduler.reductions.max(1) {
+                break Ok(ExitReason::Yielded);
+            }
+            let result = self.step();
+            // The YIELD or EXIT that ends a turn counts as well.
+            match result {
+                Ok(ExitReason::Stepped) => executed += 1,
+                Ok(ExitReason::EndOfProgram | ExitReason::Blocked | ExitReason::OutOfFuel) | Err(_) => break result,
+                Ok(_) => {
+                    executed += 1;
+                    break result;
+                }
+            }
+        };
+        self.scheduler.charge(executed);
         result
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result =
-            ((self.program[self.pcounter] as u16) << 8) | self.program[self.pcounter + 1] as u16;
-        self.pcounter += 2;
+    /// Parks the running process and loads the next one in the run queue.
+    fn switch_process(&mut self) {
+        if self.scheduler.run_queue().next().is_none() {
+            return;
+        }
+        let context = Context {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            pcounter: self.pcounter,
+            remainder: self.remainder,
+            flags: self.flags,
+            stack: std::mem::take(&mut self.stack),
+        };
+        let next = self.scheduler.switch(context).expect("run queue is not empty");
+        self.registers = next.registers;
+        self.float_registers = next.float_registers;
+        self.pcounter = next.pcounter;
+        self.remainder = next.remainder;
+        self.flags = next.flags;
+        self.stack = next.stack;
+        self.instruction_start = next.pcounter;
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+    }
+    /// Runs for at most `budget` instructions. Whatever is left of the budget
+    /// stays in `fuel` afterwards.
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<ExitReason, VmTrap> {
+        self.fuel = Some(budget);
+        self.run()
+    }
+    /// Executes a single instruction. On a trap the program counter is left
+    /// pointing at the faulting instruction and any gas it was charged is
+    /// given back.
+    pub fn run_once(&mut self) -> Result<ExitReason, VmTrap> {
+        self.instruction_start = self.pcounter;
+        let gas = self.gas;
+        if !self.observers.is_empty() {
+            if let Some(instruction) = DecodedInstruction::decode(&self.program, self.pcounter) {
+                self.observers.before_instruction(&instruction);
+            }
+        }
+        if self.history.is_some() {
+            self.begin_recording();
+        }
+        let result = self.execute_instruction();
+        if result.is_err() || result == Ok(ExitReason::Blocked) {
+            self.pcounter = self.instruction_start;
+            self.gas = gas;
+        }
+        if let Some(history) = self.history.as_mut() {
+            match result {
+                Ok(ExitReason::EndOfProgram) | Ok(ExitReason::Blocked) | Err(_) => history.discard(),
+                Ok(_) => history.end(),
+            }
+        }
+        if !self.observers.is_empty() {
+            self.notify_result(&result);
+            if self.observers.pause_requested() && result == Ok(ExitReason::Stepped) {
+                return Ok(ExitReason::Paused);
+            }
+        }
         result
     }
+    fn begin_recording(&mut self) {
+        let opcode = match self.program.get(self.pcounter) {
+            Some(byte) => Opcode::from(*byte),
+            None => Opcode::HLT,
+        };
+        let checkpoint = match &self.history {
+            Some(history) if history.wants_checkpoint(opcode) => Some(self.snapshot()),
+            _ => None,
+        };
+        let delta = Delta {
+            pcounter: self.pcounter,
+            flags: self.flags,
+            remainder: self.remainder,
+            gas: self.gas,
+            registers: vec![],
+            float_registers: vec![],
+            heap: vec![],
+            stack_len: self.stack.len(),
+            popped: vec![],
+        };
+        if let Some(history) = self.history.as_mut() {
+            history.begin(opcode, checkpoint, delta);
+        }
+    }
+    /// Undoes the most recently recorded instruction. Returns false when
+    /// nothing is recorded. Observers are not told about the changes.
+    pub fn step_back(&mut self) -> bool {
+        let undo = match self.history.as_mut().and_then(|history| history.pop()) {
+            Some(undo) => undo,
+            None => return false,
+        };
+        match undo {
+            Undo::Delta(delta) => {
+                for (register, old) in delta.registers.into_iter().rev() {
+                    self.registers[register] = old;
+                }
+                for (register, old) in delta.float_registers.into_iter().rev() {
+                    self.float_registers[register] = old;
+                }
+                for (address, old) in delta.heap.into_iter().rev() {
+                    self.heap[address..address + old.len()].copy_from_slice(&old);
+                }
+                self.stack.extend(delta.popped.into_iter().rev());
+                self.stack.truncate(delta.stack_len);
+                self.pcounter = delta.pcounter;
+                self.flags = delta.flags;
+                self.remainder = delta.remainder;
+                self.gas = delta.gas;
+            }
+            Undo::Checkpoint(snapshot) => self.restore_keeping_fuel(&snapshot),
+        }
+        self.instruction_start = self.pcounter;
+        true
+    }
+    /// Goes back to the earliest recorded state and clears the recording.
+    pub fn rewind(&mut self) -> bool {
+        match self.history.as_mut().and_then(|history| history.rewind()) {
+            Some(snapshot) => {
+                self.restore_keeping_fuel(&snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+    /// Fuel is the host's budget rather than program state, so going back
+    /// in time does not refund it.
+    fn restore_keeping_fuel(&mut self, snapshot: &Snapshot) {
+        let fuel = self.fuel;
+        self.restore(snapshot);
+        self.fuel = fuel;
+    }
+    fn notify_result(&mut self, result: &Result<ExitReason, VmTrap>) {
+        let pc = self.instruction_start;
+        match result {
+            Ok(ExitReason::Blocked) => {}
+            Ok(reason) => {
+                if let Some(instruction) = DecodedInstruction::decode(&self.program, pc) {
+                    self.observers.after_instruction(&instruction);
+                }
+                if let ExitReason::Halted | ExitReason::EndOfProgram | ExitReason::Exited = reason {
+                    self.observers.on_halt(pc, *reason);
+                }
+            }
+            Err(trap) => self.observers.on_trap(trap),
+        }
+    }
+    fn read_register(&mut self, register: usize) -> i32 {
+        if !self.observers.is_empty() {
+            self.observers.register_read(register, self.registers[register]);
+        }
+        self.registers[register]
+    }
+    fn next_register_value(&mut self) -> Result<i32, VmTrap> {
+        let register = self.next_register()?;
+        Ok(self.read_register(register))
+    }
+    fn next_float_register_value(&mut self) -> Result<f64, VmTrap> {
+        let register = self.next_float_register()?;
+        if !self.observers.is_empty() {
+            self.observers.float_register_read(register, self.float_registers[register]);
+        }
+        Ok(self.float_registers[register])
+    }
+    fn set_register(&mut self, register: usize, value: i32) {
+        if !self.observers.is_empty() {
+            self.observers.register_write(register, self.registers[register], value);
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.record_register(register, self.registers[register]);
+        }
+        self.registers[register] = value;
+    }
+    fn set_float_register(&mut self, register: usize, value: f64) {
+        if !self.observers.is_empty() {
+            self.observers.float_register_write(register, self.float_registers[register], value);
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.record_float_register(register, self.float_registers[register]);
+        }
+        self.float_registers[register] = value;
+    }
+    fn charge_gas(&mut self, cost: u64) -> Result<(), VmTrap> {
+        if let Some(remaining) = self.gas {
+            if cost > remaining {
+                return Err(VmTrap::OutOfGas { pc: self.instruction_start, required: cost, remaining });
+            }
+            self.gas = Some(remaining - cost);
+        }
+        Ok(())
+    }
+    fn next_8_bits(&mut self) -> Result<u8, VmTrap> {
+        let result = *self
+            .program
+            .get(self.pcounter)
+            .ok_or(VmTrap::PcOutOfBounds { pc: self.instruction_start })?;
+        self.pcounter += 1;
+        Ok(result)
+    }
+
+    fn next_16_bits(&mut self) -> Result<u16, VmTrap> {
+        let high = self.next_8_bits()? as u16;
+        let low = self.next_8_bits()? as u16;
+        Ok((high << 8) | low)
+    }
+
+    fn next_64_bits(&mut self) -> Result<u64, VmTrap> {
+        let high = self.next_16_bits()? as u64;
+        let mid_high = self.next_16_bits()? as u64;
+        let mid_low = self.next_16_bits()? as u64;
+        let low = self.next_16_bits()? as u64;
+        Ok((high << 48) | (mid_high << 32) | (mid_low << 16) | low)
+    }
+
+    fn next_float_register(&mut self) -> Result<usize, VmTrap> {
+        let register = self.next_8_bits()?;
+        if register as usize >= self.float_registers.len() {
+            return Err(VmTrap::BadRegister {
+                pc: self.instruction_start,
+                register,
+            });
+        }
+        Ok(register as usize)
+    }
+
+    fn next_register(&mut self) -> Result<usize, VmTrap> {
+        let register = self.next_8_bits()?;
+        if register as usize >= self.registers.len() {
+            return Err(VmTrap::BadRegister {
+                pc: self.instruction_start,
+                register,
+            });
+        }
+        Ok(register as usize)
+    }
+
+    fn jump_to(&mut self, target: i64) -> Result<(), VmTrap> {
+        self.pcounter = self.jump_target(target)?;
+        Ok(())
+    }
+    /// Checks that `target` is a valid program counter without jumping.
+    fn jump_target(&self, target: i64) -> Result<usize, VmTrap> {
+        if target < 0 || target as usize > self.program.len() {
+            return Err(VmTrap::PcOutOfBounds { pc: self.instruction_start });
+        }
+        Ok(target as usize)
+    }
+    /// Reads a sign-extended 16-bit immediate operand.
+    fn next_immediate(&mut self) -> Result<i32, VmTrap> {
+        Ok(self.next_16_bits()? as i16 as i32)
+    }
+
+    /// Sets the flags for `EQ`, `NEQ`, `GT`, `LT`, `GTQ` or `LTQ`.
+    ///
+    /// Unlike `CMP`, these test a single condition and set ZERO exactly
+    /// when it holds, so `JEQ` after any of them branches when the
+    /// comparison was true and `JNEQ` when it was false.
+    fn compare(&mut self, opcode: Opcode, register1: i32, register2: i32) {
+        let held = match opcode {
+            Opcode::EQ => register1 == register2,
+            Opcode::NEQ => register1 != register2,
+            Opcode::GT => register1 > register2,
+            Opcode::LT => register1 < register2,
+            Opcode::GTQ => register1 >= register2,
+            Opcode::LTQ => register1 <= register2,
+            _ => unreachable!("{:?} is not a comparison", opcode),
+        };
+        self.flags = Flags::compare(held as i32, true as i32);
+    }
+
+    /// Stores the quotient in `dst` and the remainder in `remainder`. The
+    /// caller has already ruled out a zero divisor.
+    fn divide(&mut self, dst: usize, register1: i32, register2: i32) {
+        let (result, overflow) = register1.overflowing_div(register2);
+        self.set_register(dst, result);
+        self.remainder = register1.wrapping_rem(register2) as u32;
+        self.flags = Flags::from_result(result, false, overflow);
+    }
+
+    /// Executes `reg = reg op immediate` with the same flag semantics as the
+    /// three-register form.
+    fn arithmetic_immediate(&mut self, op: fn(i32, i32) -> (i32, bool, bool)) -> Result<(), VmTrap> {
+        let reg = self.next_register()?;
+        let immediate = self.next_immediate()?;
+        let (result, carry, overflow) = op(self.read_register(reg), immediate);
+        self.flags = Flags::from_result(result, carry, overflow);
+        self.set_register(reg, result);
+        Ok(())
+    }
+
+    /// Reads a jump target register and jumps to it if `condition` holds,
+    /// otherwise steps over the instruction.
+    fn jump_if(&mut self, condition: bool) -> Result<(), VmTrap> {
+        let target = self.next_register_value()?;
+        self.next_8_bits()?;
+        self.next_8_bits()?;
+        if condition {
+            self.jump_to(target as i64)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a signed offset register and, if `condition` holds, jumps that
+    /// many bytes from the start of the instruction.
+    fn branch_if(&mut self, condition: bool) -> Result<(), VmTrap> {
+        let offset = self.next_register_value()?;
+        self.next_8_bits()?;
+        self.next_8_bits()?;
+        if condition {
+            self.jump_to(self.instruction_start as i64 + offset as i64)?;
+        }
+        Ok(())
+    }
+
+    /// Executes a three-register arithmetic instruction with wrapping
+    /// semantics. Checked variants trap on signed overflow instead of
+    /// writing the wrapped result.
+    fn arithmetic(&mut self, op: fn(i32, i32) -> (i32, bool, bool), checked: bool) -> Result<(), VmTrap> {
+        let register1 = self.next_register_value()?;
+        let register2 = self.next_register_value()?;
+        let dst = self.next_register()?;
+        let (result, carry, overflow) = op(register1, register2);
+        if checked && overflow {
+            return Err(VmTrap::ArithmeticOverflow { pc: self.instruction_start });
+        }
+        self.flags = Flags::from_result(result, carry, overflow);
+        self.set_register(dst, result);
+        Ok(())
+    }
+
+    fn push(&mut self, value: i32) -> Result<(), VmTrap> {
+        if self.stack.len() >= self.stack_limit {
+            return Err(VmTrap::StackOverflow { pc: self.instruction_start });
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i32, VmTrap> {
+        let value = self
+            .stack
+            .pop()
+            .ok_or(VmTrap::StackUnderflow { pc: self.instruction_start })?;
+        if let Some(history) = self.history.as_mut() {
+            history.record_pop(value);
+        }
+        Ok(value)
+    }
+    /// Resolves `base + offset` to a heap address with `width` readable bytes.
+    fn heap_address(&self, base: i32, offset: i32, width: usize) -> Result<usize, VmTrap> {
+        let address = base as i64 + offset as i64;
+        if address < 0 || address as usize + width > self.heap.len() {
+            return Err(VmTrap::HeapOutOfBounds {
+                pc: self.instruction_start,
+                address,
+            });
+        }
+        Ok(address as usize)
+    }
+
+    /// Reads a big-endian, zero-extended value of `width` bytes from the heap.
+    fn load(&mut self, width: usize) -> Result<(), VmTrap> {
+        let reg = self.next_register()?;
+        let base = self.next_register_value()?;
+        let offset = self.next_register_value()?;
+        let address = self.heap_address(base, offset, width)?;
+        if !self.observers.is_empty() {
+            self.observers.heap_read(address, &self.heap[address..address + width]);
+        }
+        let value = self.heap[address..address + width]
+            .iter()
+            .fold(0u32, |acc, byte| (acc << 8) | *byte as u32);
+        self.set_register(reg, value as i32);
+        Ok(())
+    }
+
+    /// Writes the low `width` bytes of a register to the heap, big-endian.
+    fn store(&mut self, width: usize) -> Result<(), VmTrap> {
+        let value = self.next_register_value()?;
+        let base = self.next_register_value()?;
+        let offset = self.next_register_value()?;
+        let address = self.heap_address(base, offset, width)?;
+        let bytes = value.to_be_bytes();
+        self.write_heap(address, &bytes[4 - width..]);
+        Ok(())
+    }
+    /// Overwrites heap bytes at an address that has already been checked.
+    fn write_heap(&mut self, address: usize, bytes: &[u8]) {
+        let end = address + bytes.len();
+        if !self.observers.is_empty() {
+            self.observers.heap_write(address, &self.heap[address..end], bytes);
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.record_heap(address, &self.heap[address..end]);
+        }
+        self.heap[address..end].copy_from_slice(bytes);
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            float_registers: self.float_registers,
+            pcounter: self.pcounter,
+            program: self.program.clone(),
+            remainder: self.remainder,
+            flags: self.flags,
+            heap: self.heap.clone(),
+            allocator: self.allocator.clone(),
+            gc: self.gc.clone(),
+            stack: self.stack.clone(),
+            fuel: self.fuel,
+            gas: self.gas,
+            scheduler: self.scheduler.clone(),
+        }
+    }
+
+    /// Puts the VM back into the state captured by `snapshot`.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers = snapshot.registers;
+        self.float_registers = snapshot.float_registers;
+        self.pcounter = snapshot.pcounter;
+        self.program.clone_from(&snapshot.program);
+        self.remainder = snapshot.remainder;
+        self.flags = snapshot.flags;
+        self.heap.clone_from(&snapshot.heap);
+        self.allocator.clone_from(&snapshot.allocator);
+        self.gc.clone_from(&snapshot.gc);
+        self.stack.clone_from(&snapshot.stack);
+        self.fuel = snapshot.fuel;
+        self.gas = snapshot.gas;
+        self.scheduler.clone_from(&snapshot.scheduler);
+        self.instruction_start = snapshot.pcounter;
+    }
+
+    /// Writes the VM to `path` in the format described in `persist`.
+    /// Observers are not saved.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), PersistError> {
+        fs::write(path, persist::encode(self))?;
+        Ok(())
+    }
+
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<VM, PersistError> {
+        persist::decode(&fs::read(path)?)
+    }
+
+    /// Registers a host function for `SYSCALL #number`; see `syscall` for
+    /// the calling convention.
+    pub fn register_syscall<F>(&mut self, number: u16, function: F)
+    where
+        F: FnMut(&mut SyscallContext) -> Result<i32, SyscallError> + 'static,
+    {
+        self.syscalls.register(number, function);
+    }
+
+    fn syscall(&mut self, number: u16) -> Result<(), VmTrap> {
+        let pc = self.instruction_start;
+        let function = self
+            .syscalls
+            .get_mut(number)
+            .ok_or(VmTrap::UnknownSyscall { pc, number })?;
+        // A trap must leave no writes behind, so keep what the host
+        // function could change until it has succeeded.
+        let registers = self.registers;
+        let float_registers = self.float_registers;
+        let heap = self.heap.clone();
+        let mut context = SyscallContext {
+            registers: &mut self.registers,
+            float_registers: &mut self.float_registers,
+            heap: &mut self.heap,
+        };
+        let result = match function(&mut context) {
+            Ok(result) => result,
+            Err(error) => {
+                self.registers = registers;
+                self.float_registers = float_registers;
+                self.heap = heap;
+                return Err(match error {
+                    SyscallError::BadAddress { address } => VmTrap::HeapOutOfBounds { pc, address },
+                    SyscallError::Failed(message) => VmTrap::SyscallFailed { pc, number, message },
+                });
+            }
+        };
+        self.set_register(RETURN_REGISTER, result);
+        Ok(())
+    }
+
+    /// Replaces the output sink, e.g. with a `SharedBuffer` to capture what
+    /// a program prints.
+    pub fn set_output<W: Write + 'static>(&mut self, writer: W) {
+        self.output = Output::new(writer);
+    }
+
+    /// Replaces the input source, e.g. with an `io::Cursor` holding
+    /// scripted input.
+    pub fn set_input<I: InputSource + 'static>(&mut self, source: I) {
+        self.input = Input::new(source);
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), VmTrap> {
+        self.output.emit(bytes).map_err(|e| self.io_trap(e))
+    }
+
+    fn io_trap(&self, error: io::Error) -> VmTrap {
+        VmTrap::Io {
+            pc: self.instruction_start,
+            message: error.to_string(),
+        }
+    }
+
+    fn file_trap(&self, error: FileError) -> VmTrap {
+        match error {
+            FileError::Escape(path) => VmTrap::PathEscape {
+                pc: self.instruction_start,
+                path,
+            },
+            FileError::BadDescriptor(fd) => VmTrap::BadFileDescriptor {
+                pc: self.instruction_start,
+                fd,
+            },
+            FileError::Io(error) => self.io_trap(error),
+        }
+    }
+
+    /// Socket failures become traps, except for `WouldBlock` which is
+    /// returned as `Ok(None)` so the instruction can report `Blocked`.
+    fn net_result<T>(&self, result: Result<T, NetError>) -> Result<Option<T>, VmTrap> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(NetError::WouldBlock) => Ok(None),
+            Err(NetError::Denied(address)) => Err(VmTrap::AddressDenied {
+                pc: self.instruction_start,
+                address: address.to_string(),
+            }),
+            Err(NetError::BadDescriptor(fd)) => Err(VmTrap::BadFileDescriptor {
+                pc: self.instruction_start,
+                fd,
+            }),
+            Err(NetError::Io(error)) => Err(self.io_trap(error)),
+        }
+    }
+
+    /// The heap bytes of a string: `len` bytes from `address`, or up to
+    /// the next NUL byte if `len` is zero or negative.
+    fn heap_string(&mut self, address: i32, len: i32) -> Result<Range<usize>, VmTrap> {
+        let start = self.heap_address(address, 0, 0)?;
+        let end = if len > 0 {
+            self.heap_address(address, 0, len as usize)? + len as usize
+        } else {
+            let nul = self.heap[start..].iter().position(|byte| *byte == 0).ok_or(VmTrap::HeapOutOfBounds {
+                pc: self.instruction_start,
+                address: self.heap.len() as i64,
+            })?;
+            start + nul
+        };
+        if !self.observers.is_empty() {
+            self.observers.heap_read(start, &self.heap[start..end]);
+        }
+        Ok(start..end)
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.allocator.stats(&self.heap)
+    }
+
+    /// Runs a full collection with the registers and stack of every
+    /// process as roots.
+    pub fn collect_garbage(&mut self) -> GcReport {
+        let roots = self
+            .registers
+            .iter()
+            .chain(self.stack.iter())
+            .copied()
+            .chain(self.scheduler.roots());
+        self.gc.collect(&mut self.allocator, &mut self.heap, roots)
+    }
+
+    fn collect_if_due(&mut self) {
+        if self.gc.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    fn alloc_trap(&self, error: AllocError, requested: i64, address: i64) -> VmTrap {
+        match error {
+            AllocError::OutOfMemory => VmTrap::HeapOverflow {
+                pc: self.instruction_start,
+                requested,
+            },
+            AllocError::InvalidAddress => VmTrap::InvalidHeapAddress {
+                pc: self.instruction_start,
+                address,
+            },
+        }
+    }
     pub fn add_byte(&mut self, b: u8) {
         self.program.push(b);
     }
@@ -55,152 +791,584 @@ impl VM {
         self.program.append(&mut b);
     }
 
-    fn execute_instruction(&mut self) -> bool {
-        if self.pcounter >= self.program.len() {
-            // If this happens, something broke
-            return true;
+    fn execute_instruction(&mut self) -> Result<ExitReason, VmTrap> {
+        if self.pcounter == self.program.len() {
+            return Ok(ExitReason::EndOfProgram);
         }
-        match self.get_opcode() {
+        let opcode = self.get_opcode()?;
+        self.charge_gas(self.gas_costs.cost(opcode))?;
+        match opcode {
             Opcode::SET => {
-                let register = self.next_8_bits() as usize;
-                let number = i32::from(self.next_16_bits());
-                self.registers[register] = number;
+                let register = self.next_register()?;
+                let number = i32::from(self.next_16_bits()?);
+                self.set_register(register, number);
             }
             Opcode::HLT => {
-                println!("HLT encountered");
-                return true;
+                return Ok(ExitReason::Halted);
             }
-            Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
-            },
-            Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
-            },
-            Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
-            },
+            Opcode::ADD => self.arithmetic(add_with_flags, false)?,
+            Opcode::SUB => self.arithmetic(sub_with_flags, false)?,
+            Opcode::MUL => self.arithmetic(mul_with_flags, false)?,
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as u32;
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                if register2 == 0 {
+                    return Err(VmTrap::DivideByZero { pc: self.instruction_start });
+                }
+                let dst = self.next_register()?;
+                self.divide(dst, register1, register2);
             },
             Opcode::JMP => {
-                self.pcounter = self.registers[self.next_8_bits() as usize] as usize;
+                let target = self.next_register_value()?;
+                self.jump_to(target as i64)?;
             },
             Opcode::JMPF => {
-                self.pcounter += self.registers[self.next_8_bits() as usize] as usize;
+                let offset = self.next_register_value()?;
+                self.jump_to(self.pcounter as i64 + offset as i64)?;
             },
             Opcode::JMPB => {
-                self.pcounter -= self.registers[self.next_8_bits() as usize] as usize;
-            },
-            Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 == register2 {
-                    self.is_equal = true
-                } else  {
-                    self.is_equal = false
-                }
-                self.next_8_bits();
+                let offset = self.next_register_value()?;
+                self.jump_to(self.pcounter as i64 - offset as i64)?;
             },
-            Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 != register2 {
-                    self.is_equal = true
-                } else  {
-                    self.is_equal = false
-                }
-                self.next_8_bits();
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTQ
+            | Opcode::LTQ => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.compare(opcode, register1, register2);
             },
-            Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 > register2 {
-                    self.is_greater = true
-                } else  {
-                    self.is_greater = false
-                }
-                self.next_8_bits();
+            Opcode::CMP => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.flags = Flags::compare(register1, register2);
             },
-            Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 < register2 {
-                    self.is_greater = true
-                } else  {
-                    self.is_greater = false
+            Opcode::JEQ => self.jump_if(self.flags.equal())?,
+            Opcode::JNEQ => self.jump_if(!self.flags.equal())?,
+            Opcode::JGT => self.jump_if(self.flags.greater())?,
+            Opcode::JLT => self.jump_if(self.flags.less())?,
+            Opcode::JGE => self.jump_if(self.flags.greater_or_equal())?,
+            Opcode::JLE => self.jump_if(self.flags.less_or_equal())?,
+            Opcode::JGTR => self.branch_if(self.flags.greater())?,
+            Opcode::JLTR => self.branch_if(self.flags.less())?,
+            Opcode::JGER => self.branch_if(self.flags.greater_or_equal())?,
+            Opcode::JLER => self.branch_if(self.flags.less_or_equal())?,
+            Opcode::NOP => {
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+            }
+            Opcode::ALOC => {
+                let bytes = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.next_8_bits()?;
+                if bytes < 0 {
+                    return Err(self.alloc_trap(AllocError::OutOfMemory, bytes as i64, 0));
                 }
-                self.next_8_bits();
-            },
-            Opcode::GTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 >= register2 {
-                    self.is_equal = true;
-                    self.is_greater = true;
-                } else  {
-                    self.is_equal = false;
-                    self.is_greater = false;
+                self.charge_gas(self.gas_costs.heap_cost(bytes as usize))?;
+                self.collect_if_due();
+                let address = self
+                    .allocator
+                    .allocate(&mut self.heap, bytes as usize, self.heap_limit)
+                    .map_err(|e| self.alloc_trap(e, bytes as i64, 0))?;
+                self.gc.record_allocation(bytes as usize);
+                self.set_register(dst, address as i32);
+            }
+            Opcode::FREE => {
+                let address = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                if address < 0 {
+                    return Err(self.alloc_trap(AllocError::InvalidAddress, 0, address as i64));
                 }
-                self.next_8_bits();
-            },
-            Opcode::LTQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                if register1 <= register2 {
-                    self.is_equal = true;
-                    self.is_greater = true;
-                } else  {
-                    self.is_equal = false;
-                    self.is_greater = false;
+                self.allocator
+                    .free(&mut self.heap, address as usize)
+                    .map_err(|e| self.alloc_trap(e, 0, address as i64))?;
+            }
+            Opcode::REALLOC => {
+                let address = self.next_register_value()?;
+                let bytes = self.next_register_value()?;
+                let dst = self.next_register()?;
+                if address < 0 {
+                    return Err(self.alloc_trap(AllocError::InvalidAddress, 0, address as i64));
                 }
-                self.next_8_bits();
-            },
-            Opcode::JEQ => {
-                if self.is_equal {
-                    self.pcounter = self.registers[self.next_8_bits() as usize] as usize;
+                if bytes < 0 {
+                    return Err(self.alloc_trap(AllocError::OutOfMemory, bytes as i64, 0));
                 }
-            },
-            Opcode::JNEQ => {
-                if !self.is_equal {
-                    self.pcounter = self.registers[self.next_8_bits() as usize] as usize;
+                // Only the bytes the block grows by are new allocation.
+                let old_len = self.allocator.block(address as usize).map_or(0, |block| block.size);
+                let growth = (bytes as usize).saturating_sub(old_len);
+                self.charge_gas(self.gas_costs.heap_cost(growth))?;
+                self.collect_if_due();
+                let new_address = self
+                    .allocator
+                    .reallocate(&mut self.heap, address as usize, bytes as usize, self.heap_limit)
+                    .map_err(|e| self.alloc_trap(e, bytes as i64, address as i64))?;
+                self.gc.record_allocation(growth);
+                self.set_register(dst, new_address as i32);
+            }
+            Opcode::GC => {
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.collect_garbage();
+            }
+            Opcode::FSET => {
+                let register = self.next_float_register()?;
+                let number = f64::from_bits(self.next_64_bits()?);
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.set_float_register(register, number);
+            }
+            Opcode::FADD => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let dst = self.next_float_register()?;
+                self.set_float_register(dst, register1 + register2);
+            }
+            Opcode::FSUB => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let dst = self.next_float_register()?;
+                self.set_float_register(dst, register1 - register2);
+            }
+            Opcode::FMUL => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let dst = self.next_float_register()?;
+                self.set_float_register(dst, register1 * register2);
+            }
+            Opcode::FDIV => {
+                // Division by zero follows IEEE 754 and yields an infinity or NaN.
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                let dst = self.next_float_register()?;
+                self.set_float_register(dst, register1 / register2);
+            }
+            Opcode::FCMP => {
+                let register1 = self.next_float_register_value()?;
+                let register2 = self.next_float_register_value()?;
+                self.flags = Flags::compare_float(register1, register2);
+                self.next_8_bits()?;
+            }
+            Opcode::ITOF => {
+                let dst = self.next_float_register()?;
+                let value = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.set_float_register(dst, value as f64);
+            }
+            Opcode::FTOI => {
+                // Truncates toward zero, saturating at the i32 range; NaN becomes 0.
+                let dst = self.next_register()?;
+                let value = self.next_float_register_value()?;
+                self.next_8_bits()?;
+                self.set_register(dst, value as i32);
+            }
+            Opcode::AND => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.set_register(dst, register1 & register2);
+            }
+            Opcode::OR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.set_register(dst, register1 | register2);
+            }
+            Opcode::XOR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.set_register(dst, register1 ^ register2);
+            }
+            Opcode::NOT => {
+                let register = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.set_register(dst, !register);
+                self.next_8_bits()?;
+            }
+            // Shift amounts are taken modulo 32.
+            Opcode::SHL => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.set_register(dst, register1.wrapping_shl(register2 as u32));
+            }
+            Opcode::SHR => {
+                let register1 = self.next_register_value()? as u32;
+                let register2 = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.set_register(dst, register1.wrapping_shr(register2 as u32) as i32);
+            }
+            Opcode::SAR => {
+                let register1 = self.next_register_value()?;
+                let register2 = self.next_register_value()?;
+                let dst = self.next_register()?;
+                self.set_register(dst, register1.wrapping_shr(register2 as u32));
+            }
+            Opcode::CADD => self.arithmetic(add_with_flags, true)?,
+            Opcode::CSUB => self.arithmetic(sub_with_flags, true)?,
+            Opcode::CMUL => self.arithmetic(mul_with_flags, true)?,
+            Opcode::ADDI => self.arithmetic_immediate(add_with_flags)?,
+            Opcode::SUBI => self.arithmetic_immediate(sub_with_flags)?,
+            Opcode::MULI => self.arithmetic_immediate(mul_with_flags)?,
+            Opcode::DIVI => {
+                let reg = self.next_register()?;
+                let immediate = self.next_immediate()?;
+                if immediate == 0 {
+                    return Err(VmTrap::DivideByZero { pc: self.instruction_start });
                 }
-            },
-            Opcode::NOP => {
-                self.next_8_bits();
-                self.next_8_bits();
-                self.next_8_bits();
+                let dividend = self.read_register(reg);
+                self.divide(reg, dividend, immediate);
             }
-            Opcode::ALOC => {
-                let reg = self.next_8_bits() as usize;
-                let bytes = self.registers[reg];
-                let new = self.heap.len() as i32 + bytes;
-                self.heap.resize(new as usize, 0)
+            Opcode::EQI
+            | Opcode::NEQI
+            | Opcode::GTI
+            | Opcode::LTI
+            | Opcode::GTQI
+            | Opcode::LTQI => {
+                let register = self.next_register_value()?;
+                let immediate = self.next_immediate()?;
+                self.compare(opcode.register_form(), register, immediate);
+            }
+            Opcode::CMPI => {
+                let register = self.next_register_value()?;
+                let immediate = self.next_immediate()?;
+                self.flags = Flags::compare(register, immediate);
             }
+            Opcode::JC => self.jump_if(self.flags.carry())?,
+            Opcode::JNC => self.jump_if(!self.flags.carry())?,
+            Opcode::JO => self.jump_if(self.flags.overflow())?,
+            Opcode::JNO => self.jump_if(!self.flags.overflow())?,
             Opcode::INC => {
-                let reg = self.next_8_bits() as usize;
-                self.registers[reg] += 1;
+                let reg = self.next_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let (result, carry, overflow) = add_with_flags(self.read_register(reg), 1);
+                self.flags = Flags::from_result(result, carry, overflow);
+                self.set_register(reg, result);
             }
             Opcode::DEC => {
-                let reg = self.next_8_bits() as usize;
-                self.registers[reg] -= 1;
+                let reg = self.next_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let (result, carry, overflow) = sub_with_flags(self.read_register(reg), 1);
+                self.flags = Flags::from_result(result, carry, overflow);
+                self.set_register(reg, result);
+            }
+            Opcode::PUSH => {
+                let value = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.push(value)?;
+            }
+            Opcode::POP => {
+                let reg = self.next_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let value = self.pop()?;
+                self.set_register(reg, value);
+            }
+            Opcode::CALL => {
+                let target = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                // Check the target first so a bad call leaves the stack alone.
+                let target = self.jump_target(target as i64)?;
+                self.push(self.pcounter as i32)?;
+                self.pcounter = target;
+            }
+            Opcode::RET => {
+                let target = *self
+                    .stack
+                    .last()
+                    .ok_or(VmTrap::StackUnderflow { pc: self.instruction_start })?;
+                let target = self.jump_target(target as i64)?;
+                self.pop()?;
+                self.pcounter = target;
+            }
+            Opcode::LOADB => self.load(1)?,
+            Opcode::LOADH => self.load(2)?,
+            Opcode::LOADW => self.load(4)?,
+            Opcode::STOREB => self.store(1)?,
+            Opcode::STOREH => self.store(2)?,
+            Opcode::STOREW => self.store(4)?,
+            Opcode::GAS => {
+                // Unmetered programs read -1; large budgets saturate.
+                let reg = self.next_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let remaining = match self.gas {
+                    Some(gas) => gas.min(i32::MAX as u64) as i32,
+                    None => -1,
+                };
+                self.set_register(reg, remaining);
+            }
+            Opcode::PRTI => {
+                let value = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.emit(value.to_string().as_bytes())?;
+            }
+            Opcode::PRTC => {
+                let value = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let c = char::from_u32(value as u32).ok_or(VmTrap::InvalidChar {
+                    pc: self.instruction_start,
+                    value,
+                })?;
+                self.emit(c.encode_utf8(&mut [0; 4]).as_bytes())?;
+            }
+            Opcode::PRTS => {
+                let address = self.next_register_value()?;
+                let len = self.next_register_value()?;
+                self.next_8_bits()?;
+                let string = self.heap_string(address, len)?;
+                let result = self.output.emit(&self.heap[string]);
+                result.map_err(|e| self.io_trap(e))?;
+            }
+            // The read instructions set the flags from the value or length
+            // they produce, with CARRY set once the input is exhausted.
+            Opcode::READI => {
+                let reg = self.next_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let line = self.input.read_line().map_err(|e| self.io_trap(e))?;
+                let value = match &line {
+                    Some(line) => {
+                        let text = String::from_utf8_lossy(line);
+                        text.trim().parse().map_err(|_| VmTrap::InvalidInput {
+                            pc: self.instruction_start,
+                            input: text.into_owned(),
+                        })?
+                    }
+                    None => 0,
+                };
+                self.set_register(reg, value);
+                self.flags = Flags::from_result(value, line.is_none(), false);
+            }
+            Opcode::READC => {
+                let reg = self.next_register()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let byte = self.input.read_byte().map_err(|e| self.io_trap(e))?;
+                let value = byte.map_or(-1, i32::from);
+                self.set_register(reg, value);
+                self.flags = Flags::from_result(value, byte.is_none(), false);
+            }
+            Opcode::READLN => {
+                // Writes at most `$cap` bytes of the line to `$addr` and the
+                // number written to `$len`, or -1 at end of input. The rest of
+                // a longer line is dropped.
+                let address = self.next_register_value()?;
+                let capacity = self.next_register_value()?;
+                let reg = self.next_register()?;
+                let start = self.heap_address(address, 0, capacity.max(0) as usize)?;
+                let line = self.input.read_line().map_err(|e| self.io_trap(e))?;
+                let len = match &line {
+                    Some(line) => {
+                        let len = line.len().min(capacity.max(0) as usize);
+                        self.write_heap(start, &line[..len]);
+                        len as i32
+                    }
+                    None => -1,
+                };
+                self.set_register(reg, len);
+                self.flags = Flags::from_result(len, line.is_none(), false);
+            }
+            Opcode::FOPEN => {
+                // The path is a NUL-terminated string; see `files` for modes.
+                let reg = self.next_register()?;
+                let address = self.next_register_value()?;
+                let mode = self.next_register_value()?;
+                let path = self.heap_string(address, 0)?;
+                let path = String::from_utf8_lossy(&self.heap[path]).into_owned();
+                let fd = self.files.open(&path, mode).map_err(|e| self.file_trap(e))?;
+                self.set_register(reg, fd);
+            }
+            Opcode::FREAD => {
+                // `$len` is replaced by the number of bytes read, with the
+                // flags set as for `READLN`.
+                let fd = self.next_register_value()?;
+                let address = self.next_register_value()?;
+                let reg = self.next_register()?;
+                let len = self.read_register(reg).max(0) as usize;
+                let start = self.heap_address(address, 0, len)?;
+                let mut buf = vec![0; len];
+                let read = self.files.read(fd, &mut buf).map_err(|e| self.file_trap(e))?;
+                self.write_heap(start, &buf[..read]);
+                self.set_register(reg, read as i32);
+                self.flags = Flags::from_result(read as i32, read == 0 && len > 0, false);
+            }
+            Opcode::FWRITE => {
+                // Takes its bytes like `PRTS`.
+                let fd = self.next_register_value()?;
+                let address = self.next_register_value()?;
+                let len = self.next_register_value()?;
+                let bytes = self.heap_string(address, len)?;
+                let result = self.files.write(fd, &self.heap[bytes]);
+                result.map_err(|e| self.file_trap(e))?;
+            }
+            Opcode::FSEEK => {
+                // `$offset` is replaced by the new position, saturating at
+                // `i32::MAX`.
+                let fd = self.next_register_value()?;
+                let reg = self.next_register()?;
+                let origin = self.next_register_value()?;
+                let offset = self.read_register(reg);
+                let position = self.files.seek(fd, offset, origin).map_err(|e| self.file_trap(e))?;
+                self.set_register(reg, position.min(i32::MAX as u64) as i32);
+            }
+            Opcode::FCLOSE => {
+                let fd = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.files.close(fd).map_err(|e| self.file_trap(e))?;
+            }
+            // Socket instructions that would wait return `Blocked` without
+            // changing anything; see `net` for how addresses are passed.
+            Opcode::SLISTEN => {
+                // `$port` is replaced by the port actually bound.
+                let reg = self.next_register()?;
+                let address = self.next_register_value()?;
+                let port_reg = self.next_register()?;
+                let port = self.read_register(port_reg);
+                let result = self.sockets.listen(address, port);
+                match self.net_result(result)? {
+                    Some((fd, port)) => {
+                        self.set_register(reg, fd);
+                        self.set_register(port_reg, port as i32);
+                    }
+                    None => return Ok(ExitReason::Blocked),
+                }
+            }
+            Opcode::SACCEPT => {
+                let reg = self.next_register()?;
+                let listener = self.next_register_value()?;
+                self.next_8_bits()?;
+                let result = self.sockets.accept(listener);
+                match self.net_result(result)? {
+                    Some(fd) => self.set_register(reg, fd),
+                    None => return Ok(ExitReason::Blocked),
+                }
+            }
+            Opcode::SCONNECT => {
+                let reg = self.next_register()?;
+                let address = self.next_register_value()?;
+                let port = self.next_register_value()?;
+                let result = self.sockets.connect(address, port);
+                match self.net_result(result)? {
+                    Some(fd) => self.set_register(reg, fd),
+                    None => return Ok(ExitReason::Blocked),
+                }
+            }
+            Opcode::SSEND => {
+                // Takes its bytes like `PRTS`; `$len` is replaced by the
+                // number actually sent.
+                let fd = self.next_register_value()?;
+                let address = self.next_register_value()?;
+                let reg = self.next_register()?;
+                let len = self.read_register(reg);
+                let bytes = self.heap_string(address, len)?;
+                let result = self.sockets.send(fd, &self.heap[bytes]);
+                match self.net_result(result)? {
+                    Some(sent) => self.set_register(reg, sent as i32),
+                    None => return Ok(ExitReason::Blocked),
+                }
+            }
+            Opcode::SRECV => {
+                // Like `FREAD`, with CARRY set once the peer has closed.
+                let fd = self.next_register_value()?;
+                let address = self.next_register_value()?;
+                let reg = self.next_register()?;
+                let len = self.read_register(reg).max(0) as usize;
+                let start = self.heap_address(address, 0, len)?;
+                let mut buf = vec![0; len];
+                let result = self.sockets.recv(fd, &mut buf);
+                let received = match self.net_result(result)? {
+                    Some(received) => received,
+                    None => return Ok(ExitReason::Blocked),
+                };
+                self.write_heap(start, &buf[..received]);
+                self.set_register(reg, received as i32);
+                self.flags = Flags::from_result(received as i32, received == 0 && len > 0, false);
+            }
+            Opcode::SCLOSE => {
+                let fd = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                let result = self.sockets.close(fd);
+                self.net_result(result)?;
+            }
+            Opcode::SPAWN => {
+                // The new process starts at `$target` with `$arg` in `$0`
+                // and its pid is written to `$pid`.
+                let reg = self.next_register()?;
+                let target = self.next_register_value()?;
+                let argument = self.next_register_value()?;
+                if target < 0 || target as usize >= self.program.len() {
+                    return Err(VmTrap::PcOutOfBounds { pc: self.instruction_start });
+                }
+                let pid = self
+                    .scheduler
+                    .spawn(target as usize, argument)
+                    .ok_or(VmTrap::TooManyProcesses { pc: self.instruction_start })?;
+                self.set_register(reg, pid);
+            }
+            Opcode::YIELD => {
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                return Ok(ExitReason::Yielded);
+            }
+            Opcode::EXIT => {
+                let code = self.next_register_value()?;
+                self.next_8_bits()?;
+                self.next_8_bits()?;
+                self.scheduler.exit(code);
+                return Ok(ExitReason::Exited);
+            }
+            Opcode::SYSCALL => {
+                let number = self.next_16_bits()?;
+                self.next_8_bits()?;
+                self.syscall(number)?;
             }
             Opcode::IGL => {
-                println!("Illegal instruction encountered");
-                return true;
+                return Err(VmTrap::IllegalOpcode {
+                    pc: self.instruction_start,
+                    byte: self.program[self.instruction_start],
+                });
             }
         }
-        false
+        Ok(ExitReason::Stepped)
     }
 }
+/// Returns the wrapping sum along with its carry and overflow flags.
+fn add_with_flags(a: i32, b: i32) -> (i32, bool, bool) {
+    let (result, overflow) = a.overflowing_add(b);
+    let carry = (a as u32).overflowing_add(b as u32).1;
+    (result, carry, overflow)
+}
+
+/// Returns the wrapping difference; carry reports an unsigned borrow.
+fn sub_with_flags(a: i32, b: i32) -> (i32, bool, bool) {
+    let (result, overflow) = a.overflowing_sub(b);
+    (result, (a as u32) < (b as u32), overflow)
+}
+
+/// Returns the wrapping product; carry and overflow are both set when the
+/// signed product does not fit in 32 bits.
+fn mul_with_flags(a: i32, b: i32) -> (i32, bool, bool) {
+    let (result, overflow) = a.overflowing_mul(b);
+    (result, overflow, overflow)
+}
+
 pub fn get_test_vm() -> VM {
     let mut test_vm = VM::new();
     test_vm.registers[0] = 5;
@@ -211,6 +1379,13 @@ pub fn get_test_vm() -> VM {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::allocator::FIRST_BLOCK;
+    use crate::console::SharedBuffer;
+    use crate::flags::Flags;
+    use crate::net::NetPolicy;
+    use crate::scheduler::ProcessState;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_create_vm() {
@@ -223,26 +1398,40 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![5, 0, 0, 0];
         test_vm.program = test_bytes;
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pcounter, 1);
     }
 
-    /*
+    #[test]
+    fn test_hlt_exit_reason() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![17, 0, 0, 0, 5, 0, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.pcounter, 5);
+    }
+
+    #[test]
+    fn test_end_of_program() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![17, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::Stepped));
+        assert_eq!(test_vm.run_once(), Ok(ExitReason::EndOfProgram));
+    }
+
     #[test]
     fn test_igl_opcode() {
         let mut test_vm = VM::new();
         let test_bytes = vec![120, 123, 12, 12];
         test_vm.program = test_bytes;
-        test_vm.run_once();
-        assert_eq!(test_vm.pcounter, 1);
-    }*/
-    // This would always fail, since igl is a illegal instruction
+        assert_eq!(test_vm.run_once(), Err(VmTrap::IllegalOpcode { pc: 0, byte: 120 }));
+        assert_eq!(test_vm.pcounter, 0);
+    }
 
     #[test]
     fn test_set_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![0, 0, 1, 244];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[0], 500);
     }
 
@@ -250,7 +1439,7 @@ mod tests {
     fn test_add_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![1, 1, 1, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 2);
     }
 
@@ -258,7 +1447,7 @@ mod tests {
     fn test_sub_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![2, 1, 1, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 0);
     }
 
@@ -266,7 +1455,7 @@ mod tests {
     fn test_mul_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![3, 1, 1, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 1);
     }
 
@@ -274,7 +1463,7 @@ mod tests {
     fn test_div_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![4, 1, 1, 2];
-        test_vm.run();
+        test_vm.run().unwrap();
         assert_eq!(test_vm.registers[2], 1);
         assert_eq!(test_vm.remainder, 0);
     }
@@ -284,7 +1473,7 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[1] = 1;
         test_vm.program = vec![6, 1, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pcounter, 1);
     }
 
@@ -293,7 +1482,7 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 2;
         test_vm.program = vec![7, 0, 0, 0, 6, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pcounter, 4);
     }
 
@@ -302,20 +1491,21 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[1] = 6;
         test_vm.program = vec![0, 0, 0, 10, 8, 1, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pcounter, 4);
     }
+
     #[test]
     fn test_eq_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![9, 0, 1, 0, 9, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.is_equal, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert_eq!(test_vm.is_equal, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
     }
 
     #[test]
@@ -324,11 +1514,11 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![10, 0, 1, 0, 10, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.is_equal, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert_eq!(test_vm.is_equal, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
     }
 
     #[test]
@@ -337,11 +1527,11 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![11, 0, 1, 0, 11, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
         test_vm.registers[1] = 9;
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
     }
 
     #[test]
@@ -350,11 +1540,11 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![12, 0, 1, 0, 12, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
     }
 
     #[test]
@@ -363,13 +1553,11 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![13, 0, 1, 0, 13, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, true);
-        assert_eq!(test_vm.is_equal, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, false);
-        assert_eq!(test_vm.is_equal, false);
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
     }
 
     #[test]
@@ -378,21 +1566,33 @@ mod tests {
         test_vm.registers[0] = 10;
         test_vm.registers[1] = 10;
         test_vm.program = vec![14, 0, 1, 0, 14, 0, 1, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, true);
-        assert_eq!(test_vm.is_equal, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
         test_vm.registers[1] = 20;
-        test_vm.run_once();
-        assert_eq!(test_vm.is_greater, true);
-        assert_eq!(test_vm.is_equal, true);
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
+    }
+
+    #[test]
+    fn test_legacy_comparison_then_jeq() {
+        // neq $0 $1; jeq $2
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 1;
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 12;
+        test_vm.program = vec![10, 0, 1, 0, 15, 2, 0, 0, 17, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 12);
     }
+
     #[test]
     fn test_jeq_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 7;
-        test_vm.is_equal = true;
+        test_vm.flags.set(Flags::ZERO, true);
         test_vm.program = vec![15, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pcounter, 7);
     }
 
@@ -400,9 +1600,9 @@ mod tests {
     fn test_jneq_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 7;
-        test_vm.is_equal = false;
+        test_vm.flags.set(Flags::ZERO, false);
         test_vm.program = vec![16, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
-        test_vm.run_once();
+        test_vm.run_once().unwrap();
         assert_eq!(test_vm.pcounter, 7);
     }
 
@@ -411,7 +1611,1216 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 3072;
         test_vm.program = vec![18, 0, 0, 0];
-        test_vm.run_once();
-        assert_eq!(test_vm.heap.len(), 3072);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.heap.len(), FIRST_BLOCK + 3072);
+        assert_eq!(test_vm.registers[0], FIRST_BLOCK as i32);
+    }
+
+    #[test]
+    fn test_aloc_returns_address() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 8;
+        test_vm.program = vec![18, 0, 2, 0, 18, 0, 3, 0, 28, 1, 3, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 4);
+        assert_eq!(test_vm.registers[3], 12);
+        assert_eq!(test_vm.heap.len(), 20);
+        assert_eq!(test_vm.heap[12], 1);
+    }
+
+    #[test]
+    fn test_free_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 8;
+        test_vm.program = vec![18, 0, 2, 0, 18, 0, 3, 0, 31, 2, 0, 0];
+        test_vm.run().unwrap();
+        let stats = test_vm.heap_stats();
+        assert_eq!(stats.bytes_in_use, 8);
+        assert_eq!(stats.bytes_free, 8);
+        assert_eq!(stats.heap_size, 20);
+        // Freeing the same block twice traps.
+        test_vm.pcounter = 8;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::InvalidHeapAddress { pc: 8, address: 4 }));
+    }
+
+    #[test]
+    fn test_realloc_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 4;
+        test_vm.registers[1] = 12;
+        test_vm.registers[4] = 9;
+        test_vm.program = vec![
+            18, 0, 2, 0, // aloc $0 $2
+            28, 4, 2, 5, // storeb $4 $2 $5
+            18, 0, 3, 0, // aloc $0 $3
+            32, 2, 1, 2, // realloc $2 $1 $2
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 12);
+        assert_eq!(test_vm.heap[12], 9);
+        assert_eq!(test_vm.heap_stats().bytes_in_use, 16);
+    }
+
+
+    #[test]
+    fn test_div_by_zero_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 0;
+        test_vm.program = vec![17, 0, 0, 0, 4, 0, 1, 2];
+        assert_eq!(test_vm.run(), Err(VmTrap::DivideByZero { pc: 4 }));
+        assert_eq!(test_vm.pcounter, 4);
+        assert_eq!(test_vm.registers[2], 0);
+    }
+
+    #[test]
+    fn test_bad_register_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![1, 0, 32, 2];
+        assert_eq!(test_vm.run(), Err(VmTrap::BadRegister { pc: 0, register: 32 }));
+    }
+
+    #[test]
+    fn test_truncated_instruction_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![0, 0, 1];
+        assert_eq!(test_vm.run(), Err(VmTrap::PcOutOfBounds { pc: 0 }));
+        assert_eq!(test_vm.pcounter, 0);
+    }
+
+    #[test]
+    fn test_jmpb_underflow_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 6;
+        test_vm.program = vec![8, 1, 0, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::PcOutOfBounds { pc: 0 }));
+    }
+
+    #[test]
+    fn test_jmp_out_of_bounds_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 100;
+        test_vm.program = vec![17, 0, 0, 0, 6, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmTrap::PcOutOfBounds { pc: 4 }));
+        assert_eq!(test_vm.pcounter, 4);
+    }
+
+    #[test]
+    fn test_storew_loadw_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[0] = -559038737;
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 2;
+        test_vm.program = vec![30, 0, 1, 2, 27, 3, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(test_vm.registers[3], -559038737);
+    }
+
+    #[test]
+    fn test_storeb_loadb_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = 0x1ff;
+        test_vm.registers[2] = 3;
+        test_vm.program = vec![28, 0, 2, 3, 25, 4, 2, 3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0, 0, 0xff]);
+        assert_eq!(test_vm.registers[4], 0xff);
+    }
+
+    #[test]
+    fn test_storeh_loadh_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[0] = -2;
+        test_vm.registers[2] = 1;
+        test_vm.program = vec![29, 0, 2, 3, 26, 4, 2, 3];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap, vec![0, 0xff, 0xfe, 0]);
+        assert_eq!(test_vm.registers[4], 0xfffe);
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[2] = 1;
+        test_vm.program = vec![27, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOutOfBounds { pc: 0, address: 2 }));
+        test_vm.registers[2] = -2;
+        test_vm.program = vec![28, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOutOfBounds { pc: 0, address: -1 }));
+        assert_eq!(test_vm.heap, vec![0; 4]);
+    }
+
+    #[test]
+    fn test_gc_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.registers[6] = 8;
+        test_vm.program = vec![
+            18, 6, 2, 0, // aloc $6 $2
+            18, 6, 3, 0, // aloc $6 $3
+            18, 6, 4, 0, // aloc $6 $4
+            30, 4, 3, 5, // storew $4 $3 $5
+            0, 2, 0, 0, // set $2 #0
+            0, 4, 0, 0, // set $4 #0
+            0, 6, 0, 0, // set $6 #0
+            33, 0, 0, 0, // gc
+        ];
+        test_vm.run().unwrap();
+        // $3 is a root and the block stored in it is only reachable from the heap.
+        let report = test_vm.gc.stats.last.unwrap();
+        assert_eq!(report.live_blocks, 2);
+        assert_eq!(report.freed_blocks, 1);
+        assert!(test_vm.allocator.block(FIRST_BLOCK).unwrap().free);
+        test_vm.registers[3] = 0;
+        assert_eq!(test_vm.collect_garbage().freed_blocks, 2);
+        assert_eq!(test_vm.heap_stats().live_blocks, 0);
+        assert_eq!(test_vm.heap.len(), FIRST_BLOCK);
+        assert_eq!(test_vm.gc.stats.collections, 2);
+    }
+
+    #[test]
+    fn test_gc_stack_roots() {
+        let mut test_vm = VM::new();
+        test_vm.registers[6] = 4;
+        test_vm.program = vec![
+            18, 6, 2, 0, // aloc $6 $2
+            21, 2, 0, 0, // push $2
+            0, 2, 0, 0, // set $2 #0
+            0, 6, 0, 0, // set $6 #0
+            33, 0, 0, 0, // gc
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.gc.stats.last.unwrap().freed_blocks, 0);
+        test_vm.stack.clear();
+        assert_eq!(test_vm.collect_garbage().freed_blocks, 1);
+    }
+
+    #[test]
+    fn test_gc_automatic_collection() {
+        let mut test_vm = VM::new();
+        test_vm.gc.enabled = true;
+        test_vm.gc.threshold = 2;
+        test_vm.registers[6] = 2;
+        test_vm.program = vec![18, 6, 1, 0, 18, 6, 1, 0, 18, 6, 1, 0];
+        test_vm.run().unwrap();
+        // The third allocation reclaims the first block, whose only
+        // reference in $1 was overwritten by the second, and reuses it.
+        assert_eq!(test_vm.gc.stats.collections, 2);
+        assert_eq!(test_vm.gc.stats.blocks_freed, 1);
+        assert_eq!(test_vm.registers[1], FIRST_BLOCK as i32);
+        assert_eq!(test_vm.heap_stats().live_blocks, 2);
+    }
+
+    #[test]
+    fn test_fset_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![34, 3];
+        test_vm.program.extend_from_slice(&(-2.5f64).to_be_bytes());
+        test_vm.program.extend_from_slice(&[0, 0]);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.float_registers[3], -2.5);
+        assert_eq!(test_vm.pcounter, 12);
+    }
+
+    #[test]
+    fn test_float_arithmetic_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.float_registers[0] = 7.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = vec![35, 0, 1, 2, 36, 0, 1, 3, 37, 0, 1, 4, 38, 0, 1, 5, 38, 0, 6, 6];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+        assert_eq!(test_vm.float_registers[6], f64::INFINITY);
+    }
+
+    #[test]
+    fn test_fcmp_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.float_registers[0] = 1.5;
+        test_vm.float_registers[1] = 1.5;
+        test_vm.registers[2] = 0;
+        // FCMP x3; JGT $2; JGE $2
+        test_vm.program = vec![39, 0, 1, 0, 39, 0, 1, 0, 39, 0, 1, 0, 66, 2, 0, 0, 68, 2, 0, 0];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
+        assert!(!test_vm.flags.greater());
+        test_vm.float_registers[1] = -1.0;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
+        assert!(test_vm.flags.greater());
+        test_vm.float_registers[1] = f64::NAN;
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
+        assert!(!test_vm.flags.less());
+        assert!(!test_vm.flags.greater());
+        assert!(test_vm.flags.carry());
+        // Neither jump is taken on an unordered comparison.
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 20);
+    }
+
+    #[test]
+    fn test_itof_ftoi_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -7;
+        test_vm.float_registers[1] = -3.9;
+        test_vm.float_registers[2] = 1e20;
+        test_vm.program = vec![40, 0, 0, 0, 41, 1, 1, 0, 41, 2, 2, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.float_registers[0], -7.0);
+        assert_eq!(test_vm.registers[1], -3);
+        assert_eq!(test_vm.registers[2], i32::MAX);
+    }
+
+    #[test]
+    fn test_bad_float_register_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![35, 0, 40, 1];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::BadRegister { pc: 0, register: 40 }));
+    }
+
+    #[test]
+    fn test_and_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![42, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 0b1000);
+    }
+
+    #[test]
+    fn test_or_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![43, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 0b1110);
+    }
+
+    #[test]
+    fn test_xor_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 0b1100;
+        test_vm.registers[1] = 0b1010;
+        test_vm.program = vec![44, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 0b0110);
+    }
+
+    #[test]
+    fn test_not_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![45, 0, 2, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -6);
+        assert_eq!(test_vm.pcounter, 4);
+    }
+
+    #[test]
+    fn test_shl_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 3;
+        test_vm.registers[3] = 33;
+        test_vm.program = vec![46, 0, 1, 2, 46, 0, 3, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 40);
+        assert_eq!(test_vm.registers[4], 10);
+    }
+
+    #[test]
+    fn test_shr_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![47, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 0x3fff_fffc);
+    }
+
+    #[test]
+    fn test_sar_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -16;
+        test_vm.registers[1] = 2;
+        test_vm.program = vec![48, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], -4);
+    }
+
+    #[test]
+    fn test_add_sets_carry_and_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![1, 0, 1, 2, 1, 3, 1, 4];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.flags.overflow());
+        assert!(!test_vm.flags.carry());
+        test_vm.registers[3] = -1;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], 0);
+        assert!(!test_vm.flags.overflow());
+        assert!(test_vm.flags.carry());
+    }
+
+    #[test]
+    fn test_sub_sets_carry_and_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[3] = 2;
+        test_vm.program = vec![2, 0, 1, 2, 2, 1, 3, 4];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MAX);
+        assert!(test_vm.flags.overflow());
+        assert!(!test_vm.flags.carry());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], -1);
+        assert!(!test_vm.flags.overflow());
+        assert!(test_vm.flags.carry());
+    }
+
+    #[test]
+    fn test_mul_sets_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 1 << 16;
+        test_vm.program = vec![3, 0, 0, 2, 3, 1, 1, 3];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], 0);
+        assert!(test_vm.flags.overflow());
+        assert!(test_vm.flags.carry());
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.overflow());
+        assert!(!test_vm.flags.carry());
+    }
+
+    #[test]
+    fn test_div_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        test_vm.program = vec![4, 0, 1, 2];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.flags.overflow());
+    }
+
+    #[test]
+    fn test_inc_dec_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = i32::MAX;
+        test_vm.program = vec![19, 0, 0, 0, 20, 1, 0, 0, 19, 2, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 6);
+        assert_eq!(test_vm.pcounter, 4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 0);
+        assert!(!test_vm.flags.overflow());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[2], i32::MIN);
+        assert!(test_vm.flags.overflow());
+    }
+
+    #[test]
+    fn test_checked_arithmetic_traps() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.registers[2] = 7;
+        test_vm.program = vec![49, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::ArithmeticOverflow { pc: 0 }));
+        assert_eq!(test_vm.registers[2], 7);
+        test_vm.registers[0] = i32::MIN;
+        test_vm.program = vec![50, 0, 1, 2];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::ArithmeticOverflow { pc: 0 }));
+        test_vm.program = vec![51, 0, 0, 2];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::ArithmeticOverflow { pc: 0 }));
+        test_vm.registers[0] = 6;
+        test_vm.program = vec![49, 0, 1, 2, 50, 0, 1, 3, 51, 0, 0, 4];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 7);
+        assert_eq!(test_vm.registers[3], 5);
+        assert_eq!(test_vm.registers[4], 36);
+    }
+
+    #[test]
+    fn test_carry_overflow_jumps() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 12;
+        test_vm.program = vec![52, 0, 0, 0, 53, 0, 0, 0, 54, 0, 0, 0, 55, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 4);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 12);
+        test_vm.flags.set(Flags::OVERFLOW, true);
+        test_vm.pcounter = 8;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 12);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 16);
+    }
+
+    #[test]
+    fn test_arithmetic_immediate_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = 17;
+        test_vm.program = vec![
+            56, 0, 0, 10, // addi $0 #10
+            57, 1, 255, 254, // subi $1 #-2
+            58, 0, 255, 255, // muli $0 #-1
+            59, 2, 0, 5, // divi $2 #5
+        ];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], -15);
+        assert_eq!(test_vm.registers[1], 3);
+        assert_eq!(test_vm.registers[2], 3);
+        assert_eq!(test_vm.remainder, 2);
+    }
+
+    #[test]
+    fn test_addi_sets_flags() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MAX;
+        test_vm.program = vec![56, 0, 0, 1];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[0], i32::MIN);
+        assert!(test_vm.flags.overflow());
+    }
+
+    #[test]
+    fn test_divi_by_zero_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![59, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmTrap::DivideByZero { pc: 0 }));
+    }
+
+    #[test]
+    fn test_comparison_immediate_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![60, 0, 0, 5, 61, 0, 0, 5, 62, 0, 255, 255, 63, 0, 0, 5];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
+        test_vm.run_once().unwrap();
+        assert!(!test_vm.flags.equal());
+    }
+
+    #[test]
+    fn test_jeq_not_taken() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 7;
+        test_vm.flags.set(Flags::ZERO, false);
+        test_vm.program = vec![15, 0, 0, 0, 17, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 4);
+    }
+
+    /// Runs `cmp $0 $1` followed by `branch $2`, where $2 targets the end of
+    /// the program, and reports whether the branch was taken.
+    fn branch_taken(branch: u8, left: i32, right: i32) -> bool {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = left;
+        test_vm.registers[1] = right;
+        test_vm.registers[2] = 12;
+        test_vm.program = vec![74, 0, 1, 0, branch, 2, 0, 0, 17, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.run_once().unwrap();
+        test_vm.pcounter == 12
+    }
+
+    #[test]
+    fn test_jgt_opcode() {
+        assert!(branch_taken(66, 2, 1));
+        assert!(!branch_taken(66, 1, 1));
+        assert!(!branch_taken(66, 0, 1));
+    }
+
+    #[test]
+    fn test_jlt_opcode() {
+        assert!(!branch_taken(67, 2, 1));
+        assert!(!branch_taken(67, 1, 1));
+        assert!(branch_taken(67, 0, 1));
+    }
+
+    #[test]
+    fn test_jge_opcode() {
+        assert!(branch_taken(68, 2, 1));
+        assert!(branch_taken(68, 1, 1));
+        assert!(!branch_taken(68, 0, 1));
+    }
+
+    #[test]
+    fn test_jle_opcode() {
+        assert!(!branch_taken(69, 2, 1));
+        assert!(branch_taken(69, 1, 1));
+        assert!(branch_taken(69, 0, 1));
+    }
+
+    #[test]
+    fn test_immediate_comparisons_match_register_forms() {
+        for comparison in [9u8, 10, 11, 12, 13, 14] {
+            let immediate = Opcode::from(comparison).immediate_form().unwrap() as u8;
+            for (left, right) in [(-3, 4), (4, 4), (4, -3)] {
+                let mut register_vm = get_test_vm();
+                register_vm.registers[0] = left;
+                register_vm.registers[1] = right;
+                register_vm.program = vec![comparison, 0, 1, 0];
+                register_vm.run_once().unwrap();
+                let mut immediate_vm = get_test_vm();
+                immediate_vm.registers[0] = left;
+                let [high, low] = (right as i16).to_be_bytes();
+                immediate_vm.program = vec![immediate, 0, high, low];
+                immediate_vm.run_once().unwrap();
+                assert_eq!(immediate_vm.flags, register_vm.flags, "{} {} {}", immediate, left, right);
+            }
+        }
+    }
+
+    #[test]
+    fn test_relative_branches() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = 8;
+        test_vm.registers[3] = -8;
+        test_vm.flags = Flags::compare(1, 0);
+        test_vm.program = vec![17, 0, 0, 0, 70, 2, 0, 0, 17, 0, 0, 0, 71, 3, 0, 0, 72, 3, 0, 0];
+        test_vm.pcounter = 4;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 12);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 16);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 8);
+        test_vm.registers[2] = -100;
+        test_vm.pcounter = 4;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::PcOutOfBounds { pc: 4 }));
+        test_vm.program[16] = 73;
+        test_vm.pcounter = 16;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 20);
+    }
+
+    #[test]
+    fn test_cmp_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = -1;
+        test_vm.program = vec![74, 0, 0, 0, 74, 1, 0, 0, 74, 0, 2, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags.bits(), Flags::ZERO);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags.bits(), Flags::NEGATIVE | Flags::CARRY);
+        assert!(test_vm.flags.less());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags.bits(), Flags::CARRY);
+        assert!(test_vm.flags.greater());
+    }
+
+    #[test]
+    fn test_cmp_signed_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.program = vec![74, 0, 1, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags.bits(), Flags::OVERFLOW);
+        assert!(test_vm.flags.less());
+    }
+
+    #[test]
+    fn test_cmpi_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![75, 0, 0, 6, 75, 0, 0, 5];
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.less());
+        test_vm.run_once().unwrap();
+        assert!(test_vm.flags.equal());
+    }
+
+    #[test]
+    fn test_arithmetic_sets_zero_and_negative() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![2, 1, 1, 2, 2, 1, 0, 3];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags.bits(), Flags::ZERO);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.flags.bits(), Flags::NEGATIVE | Flags::CARRY);
+        assert_eq!(test_vm.registers[3], -4);
+    }
+
+    #[test]
+    fn test_aloc_heap_overflow_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.heap_limit = 1024;
+        test_vm.registers[0] = 2048;
+        test_vm.program = vec![18, 0, 1, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOverflow { pc: 0, requested: 2048 }));
+        test_vm.registers[0] = -1;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOverflow { pc: 0, requested: -1 }));
+        assert_eq!(test_vm.heap.len(), 0);
+    }
+
+    #[test]
+    fn test_push_pop_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![21, 0, 0, 0, 21, 1, 0, 0, 22, 2, 0, 0, 22, 3, 0, 0];
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.registers[2], 1);
+        assert_eq!(test_vm.registers[3], 5);
+        assert!(test_vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_call_ret_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = 12;
+        test_vm.registers[3] = 20;
+        test_vm.program = vec![
+            23, 2, 0, 0, // call $2
+            5, 0, 0, 0, // hlt
+            1, 0, 0, 0, // unreachable: add $0 $0 $0
+            23, 3, 0, 0, // call $3
+            24, 0, 0, 0, // ret
+            1, 0, 1, 0, // add $0 $1 $0
+            24, 0, 0, 0, // ret
+        ];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.pcounter, 12);
+        assert_eq!(test_vm.stack, vec![4]);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 6);
+        assert!(test_vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_stack_overflow_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.stack_limit = 1;
+        test_vm.program = vec![21, 0, 0, 0, 21, 0, 0, 0];
+        assert_eq!(test_vm.run(), Err(VmTrap::StackOverflow { pc: 4 }));
+        assert_eq!(test_vm.stack, vec![5]);
+    }
+
+    #[test]
+    fn test_stack_underflow_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![24, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::StackUnderflow { pc: 0 }));
+        test_vm.program = vec![22, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::StackUnderflow { pc: 0 }));
+        assert_eq!(test_vm.registers[0], 5);
+    }
+
+    #[test]
+    fn test_call_ret_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = 100;
+        // CALL $2; RET
+        test_vm.program = vec![23, 2, 0, 0, 24, 0, 0, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::PcOutOfBounds { pc: 0 }));
+        assert!(test_vm.stack.is_empty());
+        test_vm.stack.push(100);
+        test_vm.pcounter = 4;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::PcOutOfBounds { pc: 4 }));
+        assert_eq!(test_vm.stack, vec![100]);
+    }
+
+    #[test]
+    fn test_run_with_budget_stops_infinite_loop() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[2] = 0;
+        test_vm.program = vec![19, 0, 0, 0, 6, 2, 0, 0];
+        assert_eq!(test_vm.run_with_budget(11), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.fuel, Some(0));
+        assert_eq!(test_vm.registers[0], 5 + 6);
+        assert_eq!(test_vm.pcounter, 4);
+        assert_eq!(test_vm.run_with_budget(2), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.registers[0], 5 + 7);
+        assert_eq!(test_vm.pcounter, 4);
+    }
+
+    #[test]
+    fn test_run_with_budget_resumes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![19, 0, 0, 0, 19, 0, 0, 0, 19, 0, 0, 0, 5];
+        assert_eq!(test_vm.run_with_budget(2), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.registers[0], 7);
+        assert_eq!(test_vm.run_with_budget(10), Ok(ExitReason::Halted));
+        assert_eq!(test_vm.registers[0], 8);
+        assert_eq!(test_vm.fuel, Some(8));
+        test_vm.fuel = None;
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+    }
+
+    #[test]
+    fn test_out_of_gas_trap() {
+        let mut test_vm = get_test_vm();
+        test_vm.gas = Some(4);
+        test_vm.gas_costs.set_cost(Opcode::MUL, 4);
+        test_vm.program = vec![19, 0, 0, 0, 3, 0, 0, 0];
+        assert_eq!(
+            test_vm.run(),
+            Err(VmTrap::OutOfGas { pc: 4, required: 4, remaining: 3 })
+        );
+        assert_eq!(test_vm.gas, Some(3));
+        assert_eq!(test_vm.registers[0], 6);
+        test_vm.gas = Some(4);
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.registers[0], 36);
+        assert_eq!(test_vm.gas, Some(0));
+    }
+
+    #[test]
+    fn test_aloc_charges_per_byte() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 100;
+        test_vm.gas = Some(100);
+        test_vm.program = vec![18, 0, 1, 0];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmTrap::OutOfGas { pc: 0, required: 100, remaining: 99 })
+        );
+        assert_eq!(test_vm.gas, Some(100));
+        assert!(test_vm.heap.is_empty());
+        test_vm.gas_costs.heap_byte_cost = 0;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.gas, Some(99));
+        assert_eq!(test_vm.registers[1], FIRST_BLOCK as i32);
+    }
+
+    #[test]
+    fn test_realloc_charges_growth() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 16;
+        test_vm.registers[1] = 24;
+        test_vm.registers[2] = 8;
+        test_vm.gas = Some(100);
+        // aloc $0 $3; realloc $3 $1 $3; realloc $3 $2 $3
+        test_vm.program = vec![18, 0, 3, 0, 32, 3, 1, 3, 32, 3, 2, 3];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.gas, Some(83));
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.gas, Some(74));
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.gas, Some(73));
+    }
+
+    #[test]
+    fn test_gas_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![76, 0, 0, 0, 76, 1, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], -1);
+        test_vm.gas = Some(10);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], 9);
+    }
+
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl VmObserver for Recorder {
+        fn before_instruction(&mut self, instruction: &DecodedInstruction) {
+            self.0.borrow_mut().push(format!("before {:?} {:?}", instruction.opcode, instruction.operands));
+        }
+        fn after_instruction(&mut self, instruction: &DecodedInstruction) {
+            self.0.borrow_mut().push(format!("after {:?}", instruction.opcode));
+        }
+        fn register_write(&mut self, register: usize, old: i32, new: i32) {
+            self.0.borrow_mut().push(format!("${} {} -> {}", register, old, new));
+        }
+        fn heap_write(&mut self, address: usize, old: &[u8], new: &[u8]) {
+            self.0.borrow_mut().push(format!("heap {} {:?} -> {:?}", address, old, new));
+        }
+        fn on_halt(&mut self, pc: usize, reason: ExitReason) {
+            self.0.borrow_mut().push(format!("{:?} at {}", reason, pc));
+        }
+        fn on_trap(&mut self, trap: &VmTrap) {
+            self.0.borrow_mut().push(format!("trap {}", trap));
+        }
+    }
+
+    #[test]
+    fn test_observer_events() {
+        let mut test_vm = VM::new();
+        let recorder = Recorder::default();
+        test_vm.observers.push(Box::new(recorder.clone()));
+        test_vm.registers[0] = 4;
+        test_vm.registers[2] = 0x0102;
+        // ALOC $0 $1; STOREH $2 $1 $3; HLT
+        test_vm.program = vec![18, 0, 1, 0, 29, 2, 1, 3, 5];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Halted));
+        assert_eq!(
+            *recorder.0.borrow(),
+            vec![
+                "before ALOC [0, 1, 0]",
+                "$1 0 -> 4",
+                "after ALOC",
+                "before STOREH [2, 1, 3]",
+                "heap 4 [0, 0] -> [1, 2]",
+                "after STOREH",
+                "before HLT []",
+                "after HLT",
+                "Halted at 8",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observer_trap() {
+        let mut test_vm = get_test_vm();
+        let recorder = Recorder::default();
+        test_vm.observers.push(Box::new(recorder.clone()));
+        test_vm.program = vec![4, 0, 2, 3];
+        assert!(test_vm.run_once().is_err());
+        assert_eq!(
+            *recorder.0.borrow(),
+            vec!["before DIV [0, 2, 3]", "trap divide by zero at 0"]
+        );
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut test_vm = get_test_vm();
+        // ALOC $0 $1; STOREW $0 $1 $2; PUSH $0; INC $0
+        test_vm.program = vec![18, 0, 1, 0, 30, 0, 1, 2, 21, 0, 0, 0, 19, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        let snapshot = test_vm.snapshot();
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(test_vm.registers[0], 6);
+        assert_eq!(test_vm.stack, vec![5]);
+        test_vm.restore(&snapshot);
+        assert_eq!(test_vm.snapshot(), snapshot);
+        assert_eq!(test_vm.pcounter, 4);
+        assert_eq!(test_vm.heap[FIRST_BLOCK..FIRST_BLOCK + 4], [0, 0, 0, 0]);
+        assert!(test_vm.stack.is_empty());
+        // The same starting point can be run again with different inputs.
+        test_vm.registers[0] = 9;
+        test_vm.run().unwrap();
+        assert_eq!(test_vm.heap[FIRST_BLOCK..FIRST_BLOCK + 4], [0, 0, 0, 9]);
+        assert_eq!(test_vm.registers[0], 10);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("crabvm-test-{}.vm", std::process::id()));
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![19, 0, 0, 0, 19, 0, 0, 0];
+        test_vm.run_once().unwrap();
+        test_vm.save_to(&path).unwrap();
+        let mut loaded = VM::load_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.snapshot(), test_vm.snapshot());
+        loaded.run().unwrap();
+        assert_eq!(loaded.registers[0], 7);
+        assert!(matches!(VM::load_from(&path), Err(PersistError::Io(_))));
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 8];
+        test_vm.register_syscall(7, |context| {
+            let sum = context.arg(0) + context.arg(1);
+            context.write(context.arg(2), &sum.to_be_bytes())?;
+            context.registers[9] = -1;
+            Ok(sum * 2)
+        });
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 4;
+        test_vm.registers[3] = 4;
+        test_vm.program = vec![77, 0, 7, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], 14);
+        assert_eq!(test_vm.registers[9], -1);
+        assert_eq!(test_vm.heap[4..], [0, 0, 0, 7]);
+    }
+
+    #[test]
+    fn test_syscall_traps() {
+        let mut test_vm = VM::new();
+        test_vm.register_syscall(1, |_| Err("no such file".into()));
+        test_vm.register_syscall(2, |context| context.read(100, 4).map(|_| 0));
+        test_vm.program = vec![77, 0, 3, 0, 77, 0, 1, 0, 77, 0, 2, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::UnknownSyscall { pc: 0, number: 3 }));
+        test_vm.pcounter = 4;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmTrap::SyscallFailed { pc: 4, number: 1, message: "no such file".to_string() })
+        );
+        test_vm.pcounter = 8;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOutOfBounds { pc: 8, address: 100 }));
+    }
+
+    #[test]
+    fn test_failed_syscall_leaves_no_writes() {
+        let mut test_vm = VM::new();
+        test_vm.heap = vec![0; 4];
+        test_vm.register_syscall(1, |context| {
+            context.registers[5] = 9;
+            context.float_registers[0] = 1.5;
+            context.write(0, b"abcd")?;
+            Err("gave up".into())
+        });
+        test_vm.program = vec![77, 0, 1, 0];
+        assert!(matches!(test_vm.run_once(), Err(VmTrap::SyscallFailed { .. })));
+        assert_eq!(test_vm.registers[5], 0);
+        assert_eq!(test_vm.float_registers[0], 0.0);
+        assert_eq!(test_vm.heap, vec![0; 4]);
+    }
+
+    #[test]
+    fn test_print_opcodes() {
+        let mut test_vm = VM::new();
+        let output = SharedBuffer::new();
+        test_vm.set_output(output.clone());
+        test_vm.heap = b"\0hi!\0".to_vec();
+        test_vm.registers[0] = -42;
+        test_vm.registers[1] = 'é' as i32;
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = 2;
+        // PRTI $0; PRTC $1; PRTS $2 $3; PRTS $2 $4
+        test_vm.program = vec![78, 0, 0, 0, 79, 1, 0, 0, 80, 2, 3, 0, 80, 2, 4, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::EndOfProgram));
+        assert_eq!(output.text(), "-42éhihi!");
+    }
+
+    #[test]
+    fn test_print_traps() {
+        let mut test_vm = VM::new();
+        test_vm.set_output(SharedBuffer::new());
+        test_vm.heap = b"abc".to_vec();
+        test_vm.registers[0] = 0xD800;
+        test_vm.registers[2] = 4;
+        test_vm.program = vec![79, 0, 0, 0, 80, 1, 2, 0, 80, 1, 3, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::InvalidChar { pc: 0, value: 0xD800 }));
+        test_vm.pcounter = 4;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOutOfBounds { pc: 4, address: 0 }));
+        test_vm.pcounter = 8;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOutOfBounds { pc: 8, address: 3 }));
+    }
+
+    #[test]
+    fn test_read_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.set_input(io::Cursor::new(" -17 \nxyz\nhello, world\n"));
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[2] = 5;
+        // READI $0; READC $1; READLN $3 $2 $4; READLN $3 $2 $5; READC $1
+        test_vm.program = vec![81, 0, 0, 0, 82, 1, 0, 0, 83, 3, 2, 4, 83, 3, 2, 5, 82, 1, 0, 0];
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[0], -17);
+        assert!(test_vm.flags.negative() && !test_vm.flags.carry());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], b'x' as i32);
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[4], 2);
+        assert_eq!(&test_vm.heap[..2], b"yz");
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[5], 5);
+        assert_eq!(&test_vm.heap[..6], b"hello\0");
+        assert!(!test_vm.flags.carry());
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[1], -1);
+        assert!(test_vm.flags.carry());
+        test_vm.pcounter = 12;
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[5], -1);
+        assert!(test_vm.flags.carry());
+    }
+
+    #[test]
+    fn test_read_traps() {
+        let mut test_vm = VM::new();
+        test_vm.set_input(io::Cursor::new("12a\nrest\n"));
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 8;
+        test_vm.program = vec![81, 0, 0, 0, 83, 0, 1, 2];
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmTrap::InvalidInput {
+                pc: 0,
+                input: "12a".to_string()
+            })
+        );
+        // An out of bounds buffer traps before any input is consumed.
+        test_vm.pcounter = 4;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::HeapOutOfBounds { pc: 4, address: 0 }));
+        assert_eq!(test_vm.input.read_line().unwrap(), Some(b"rest".to_vec()));
+    }
+
+    #[test]
+    fn test_file_opcodes() {
+        let dir = std::env::temp_dir().join(format!("crabvm-vm-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.files.set_root(&dir).unwrap();
+        test_vm.heap = b"out.txt\0hello\0\0\0\0\0".to_vec();
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = 8;
+        test_vm.registers[4] = 3;
+        // FOPEN $1 $0 $2; FWRITE $1 $3 $0; FCLOSE $1; FOPEN $1 $0 $0;
+        // FSEEK $1 $4 $0; FREAD $1 $3 $5; FREAD $1 $3 $5
+        test_vm.program = vec![
+            84, 1, 0, 2, 86, 1, 3, 0, 88, 1, 0, 0, 84, 1, 0, 0, 87, 1, 4, 0, 85, 1, 3, 5, 85, 1, 3, 5,
+        ];
+        test_vm.registers[5] = 4;
+        for _ in 0..6 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(std::fs::read(dir.join("out.txt")).unwrap(), b"hello");
+        assert_eq!(test_vm.registers[4], 3);
+        assert_eq!(test_vm.registers[5], 2);
+        assert_eq!(&test_vm.heap[8..11], b"lol");
+        test_vm.run_once().unwrap();
+        assert_eq!(test_vm.registers[5], 0);
+        assert!(test_vm.flags.zero());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_traps() {
+        let mut test_vm = VM::new();
+        test_vm.heap = b"../secret\0".to_vec();
+        test_vm.registers[2] = 7;
+        // FOPEN $1 $0 $0; FCLOSE $2
+        test_vm.program = vec![84, 1, 0, 0, 88, 2, 0, 0];
+        let escape = VmTrap::PathEscape {
+            pc: 0,
+            path: "../secret".to_string(),
+        };
+        assert_eq!(test_vm.run_once(), Err(escape.clone()));
+        test_vm.files.set_root(std::env::temp_dir()).unwrap();
+        assert_eq!(test_vm.run_once(), Err(escape));
+        test_vm.pcounter = 4;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::BadFileDescriptor { pc: 4, fd: 7 }));
+    }
+
+    #[test]
+    fn test_socket_opcodes() {
+        let mut test_vm = VM::new();
+        test_vm.sockets.policy = NetPolicy::LoopbackOnly;
+        test_vm.heap = b"hi\0\0\0\0".to_vec();
+        test_vm.registers[1] = 0x7F00_0001;
+        test_vm.registers[6] = 4;
+        // SLISTEN $0 $1 $2; SACCEPT $3 $0; SCONNECT $4 $1 $2; SACCEPT $3 $0;
+        // SRECV $3 $5 $6; SSEND $4 $7 $8; SRECV $3 $5 $6; SCLOSE $4
+        test_vm.program = vec![
+            89, 0, 1, 2, 90, 3, 0, 0, 91, 4, 1, 2, 90, 3, 0, 0, 93, 3, 5, 6, 92, 4, 7, 8, 93, 3, 5, 6, 94, 4, 0, 0,
+        ];
+        test_vm.registers[5] = 2;
+        test_vm.registers[8] = 2;
+        test_vm.run_once().unwrap();
+        assert!(test_vm.registers[2] > 0);
+        assert_eq!(test_vm.run(), Ok(ExitReason::Blocked));
+        assert_eq!(test_vm.pcounter, 4);
+        test_vm.pcounter = 8;
+        // SACCEPT blocks until the background connection is up.
+        while test_vm.pcounter < 16 {
+            assert_eq!(test_vm.run(), Ok(ExitReason::Blocked));
+        }
+        assert_eq!(test_vm.pcounter, 16);
+        test_vm.fuel = Some(1);
+        assert_eq!(test_vm.step(), Ok(ExitReason::Blocked));
+        assert_eq!(test_vm.fuel, Some(1));
+        test_vm.pcounter = 20;
+        test_vm.fuel = None;
+        while test_vm.run() == Ok(ExitReason::Blocked) {}
+        assert_eq!(test_vm.pcounter, test_vm.program.len());
+        assert_eq!(&test_vm.heap[2..4], b"hi");
+        assert_eq!(test_vm.registers[6], 2);
+        assert_eq!(test_vm.sockets.descriptors().count(), 2);
+    }
+
+    #[test]
+    fn test_socket_traps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 0x0A00_0001;
+        test_vm.registers[2] = 80;
+        // SCONNECT $0 $1 $2; SCLOSE $3
+        test_vm.program = vec![91, 0, 1, 2, 94, 3, 0, 0];
+        test_vm.sockets.policy = NetPolicy::LoopbackOnly;
+        assert_eq!(
+            test_vm.run_once(),
+            Err(VmTrap::AddressDenied {
+                pc: 0,
+                address: "10.0.0.1:80".to_string()
+            })
+        );
+        test_vm.pcounter = 4;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::BadFileDescriptor { pc: 4, fd: 0 }));
+    }
+
+    #[test]
+    fn test_spawn_yield_exit() {
+        let mut test_vm = VM::new();
+        test_vm.registers[2] = 16;
+        test_vm.registers[3] = 5;
+        // SPAWN $1 $2 $3; YIELD; INC $4; EXIT $4; child: INC $0; EXIT $0
+        test_vm.program = vec![95, 1, 2, 3, 96, 0, 0, 0, 19, 4, 0, 0, 97, 4, 0, 0, 19, 0, 0, 0, 97, 0, 0, 0];
+        assert_eq!(test_vm.run_processes(), Ok(ExitReason::Exited));
+        assert_eq!(test_vm.registers[1], 1);
+        let main = test_vm.scheduler.process(0).unwrap();
+        assert_eq!((main.state, main.reductions), (ProcessState::Exited(1), 4));
+        let child = test_vm.scheduler.process(1).unwrap();
+        assert_eq!((child.state, child.reductions), (ProcessState::Exited(6), 2));
+        assert_eq!(test_vm.run_processes(), Ok(ExitReason::Exited));
+
+        // Without the scheduler YIELD does nothing and EXIT stops the VM.
+        let mut test_vm = VM::new();
+        test_vm.program = vec![96, 0, 0, 0, 97, 0, 0, 0, 19, 0, 0, 0];
+        assert_eq!(test_vm.run(), Ok(ExitReason::Exited));
+        assert_eq!(test_vm.pcounter, 8);
+    }
+
+    #[test]
+    fn test_preemption() {
+        let mut test_vm = VM::new();
+        test_vm.scheduler.reductions = 10;
+        test_vm.registers[2] = 12;
+        test_vm.registers[3] = 12;
+        test_vm.registers[5] = 4;
+        // SPAWN $1 $2 $3; loop: INC $0; JMP $5; child: INC $1; JMP $0
+        test_vm.program = vec![95, 1, 2, 3, 19, 0, 0, 0, 6, 5, 0, 0, 19, 1, 0, 0, 6, 0, 0, 0];
+        test_vm.fuel = Some(100);
+        assert_eq!(test_vm.run_processes(), Ok(ExitReason::OutOfFuel));
+        assert_eq!(test_vm.scheduler.current(), 0);
+        assert_eq!(test_vm.registers[0], 25);
+        let child = test_vm.scheduler.process(1).unwrap();
+        assert_eq!(child.reductions, 50);
+        assert_eq!(child.context.as_ref().unwrap().registers[1], 25);
+    }
+
+    #[test]
+    fn test_processes_are_gc_roots() {
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = 8;
+        test_vm.registers[3] = 12;
+        // ALOC $0 $1; SPAWN $2 $3 $1; SET $1 #0; SET $0 #0; EXIT $0
+        test_vm.program = vec![18, 0, 1, 0, 95, 2, 3, 1, 0, 1, 0, 0, 0, 0, 0, 0, 97, 0, 0, 0];
+        for _ in 0..3 {
+            test_vm.run_once().unwrap();
+        }
+        assert_eq!(test_vm.collect_garbage().freed_blocks, 0);
+        assert_eq!(test_vm.run_processes(), Ok(ExitReason::Exited));
+        assert_eq!(test_vm.collect_garbage().freed_blocks, 1);
+    }
+
+    #[test]
+    fn test_spawn_traps() {
+        let mut test_vm = VM::new();
+        test_vm.registers[1] = 8;
+        test_vm.scheduler.process_limit = 1;
+        test_vm.program = vec![95, 0, 1, 0, 95, 0, 2, 0];
+        assert_eq!(test_vm.run_once(), Err(VmTrap::PcOutOfBounds { pc: 0 }));
+        test_vm.pcounter = 4;
+        assert_eq!(test_vm.run_once(), Err(VmTrap::TooManyProcesses { pc: 4 }));
     }
 }
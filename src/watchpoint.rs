@@ -0,0 +1,320 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::observer::{DecodedInstruction, VmObserver};
+
+/// What a watchpoint looks at.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WatchTarget {
+    Register(usize),
+    FloatRegister(usize),
+    /// `len` heap bytes starting at `start`; any access overlapping them
+    /// counts.
+    Heap { start: usize, len: usize },
+}
+
+impl WatchTarget {
+    fn overlaps(&self, other: &WatchTarget) -> bool {
+        match (*self, *other) {
+            (WatchTarget::Register(a), WatchTarget::Register(b)) => a == b,
+            (WatchTarget::FloatRegister(a), WatchTarget::FloatRegister(b)) => a == b,
+            (WatchTarget::Heap { start, len }, WatchTarget::Heap { start: other, len: other_len }) => {
+                start < other + other_len && other < start + len
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Which accesses a watchpoint fires on.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum WatchOn {
+    Read,
+    Write,
+    ReadOrWrite,
+}
+
+impl WatchOn {
+    fn matches(self, access: Access) -> bool {
+        match self {
+            WatchOn::Read => access == Access::Read,
+            WatchOn::Write => access == Access::Write,
+            WatchOn::ReadOrWrite => true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WatchValue {
+    Integer(i32),
+    Float(f64),
+    Bytes(Vec<u8>),
+}
+
+/// A single access that triggered a watchpoint. For reads `old` and `new`
+/// are both the value read.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WatchHit {
+    pub watchpoint: usize,
+    /// Address of the instruction that made the access.
+    pub pc: usize,
+    pub access: Access,
+    /// The location actually accessed, which for the heap may be wider or
+    /// narrower than the watched range.
+    pub target: WatchTarget,
+    pub old: WatchValue,
+    pub new: WatchValue,
+}
+
+pub enum WatchAction {
+    /// Record the hit and stop the VM with `ExitReason::Paused` once the
+    /// instruction completes.
+    Pause,
+    /// Call the closure and keep running. It runs while the watchpoint list
+    /// is borrowed, so it must not call back into `Watchpoints`.
+    Callback(Box<dyn FnMut(&WatchHit)>),
+}
+
+impl fmt::Debug for WatchAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchAction::Pause => write!(f, "Pause"),
+            WatchAction::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Watch {
+    id: usize,
+    target: WatchTarget,
+    on: WatchOn,
+    action: WatchAction,
+}
+
+#[derive(Debug, Default)]
+struct WatchState {
+    watches: Vec<Watch>,
+    next_id: usize,
+    pc: usize,
+    hits: Vec<WatchHit>,
+    pause: bool,
+}
+
+/// Watchpoints on registers and heap ranges.
+///
+/// This is a shared handle: attach a clone to the VM with
+/// `vm.observers.push(Box::new(watchpoints.clone()))` and keep the
+/// original to add watchpoints and collect hits while the VM runs.
+#[derive(Debug, Default, Clone)]
+pub struct Watchpoints(Rc<RefCell<WatchState>>);
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints::default()
+    }
+
+    /// Adds a watchpoint and returns its id.
+    pub fn add(&self, target: WatchTarget, on: WatchOn, action: WatchAction) -> usize {
+        let mut state = self.0.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.watches.push(Watch { id, target, on, action });
+        id
+    }
+
+    pub fn remove(&self, id: usize) -> bool {
+        let mut state = self.0.borrow_mut();
+        let before = state.watches.len();
+        state.watches.retain(|watch| watch.id != id);
+        state.watches.len() != before
+    }
+
+    pub fn clear(&self) {
+        self.0.borrow_mut().watches.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().watches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().watches.is_empty()
+    }
+
+    /// Hits recorded by pausing watchpoints, oldest first.
+    pub fn hits(&self) -> Vec<WatchHit> {
+        self.0.borrow().hits.clone()
+    }
+
+    pub fn take_hits(&self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.0.borrow_mut().hits)
+    }
+
+    fn access(&mut self, access: Access, target: WatchTarget, old: WatchValue, new: WatchValue) {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        for watch in state.watches.iter_mut() {
+            if !watch.on.matches(access) || !watch.target.overlaps(&target) {
+                continue;
+            }
+            let hit = WatchHit {
+                watchpoint: watch.id,
+                pc: state.pc,
+                access,
+                target,
+                old: old.clone(),
+                new: new.clone(),
+            };
+            match &mut watch.action {
+                WatchAction::Pause => {
+                    state.hits.push(hit);
+                    state.pause = true;
+                }
+                WatchAction::Callback(callback) => callback(&hit),
+            }
+        }
+    }
+}
+
+impl VmObserver for Watchpoints {
+    fn before_instruction(&mut self, instruction: &DecodedInstruction) {
+        self.0.borrow_mut().pc = instruction.pc;
+    }
+    fn register_read(&mut self, register: usize, value: i32) {
+        let value = WatchValue::Integer(value);
+        self.access(Access::Read, WatchTarget::Register(register), value.clone(), value);
+    }
+    fn float_register_read(&mut self, register: usize, value: f64) {
+        let value = WatchValue::Float(value);
+        self.access(Access::Read, WatchTarget::FloatRegister(register), value.clone(), value);
+    }
+    fn register_write(&mut self, register: usize, old: i32, new: i32) {
+        self.access(
+            Access::Write,
+            WatchTarget::Register(register),
+            WatchValue::Integer(old),
+            WatchValue::Integer(new),
+        );
+    }
+    fn float_register_write(&mut self, register: usize, old: f64, new: f64) {
+        self.access(
+            Access::Write,
+            WatchTarget::FloatRegister(register),
+            WatchValue::Float(old),
+            WatchValue::Float(new),
+        );
+    }
+    fn heap_read(&mut self, address: usize, bytes: &[u8]) {
+        let target = WatchTarget::Heap { start: address, len: bytes.len() };
+        let value = WatchValue::Bytes(bytes.to_vec());
+        self.access(Access::Read, target, value.clone(), value);
+    }
+    fn heap_write(&mut self, address: usize, old: &[u8], new: &[u8]) {
+        let target = WatchTarget::Heap { start: address, len: new.len() };
+        self.access(
+            Access::Write,
+            target,
+            WatchValue::Bytes(old.to_vec()),
+            WatchValue::Bytes(new.to_vec()),
+        );
+    }
+    fn pause_requested(&mut self) -> bool {
+        std::mem::take(&mut self.0.borrow_mut().pause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{ExitReason, VM};
+
+    fn watched_vm(watchpoints: &Watchpoints) -> VM {
+        let mut vm = VM::new();
+        vm.observers.push(Box::new(watchpoints.clone()));
+        vm
+    }
+
+    #[test]
+    fn test_register_write_pauses() {
+        let watchpoints = Watchpoints::new();
+        let mut vm = watched_vm(&watchpoints);
+        let id = watchpoints.add(WatchTarget::Register(2), WatchOn::Write, WatchAction::Pause);
+        // SET $1 #7; ADD $1 $1 $2; SET $3 #1
+        vm.program = vec![0, 1, 0, 7, 1, 1, 1, 2, 0, 3, 0, 1];
+        assert_eq!(vm.run(), Ok(ExitReason::Paused));
+        assert_eq!(vm.pcounter, 8);
+        assert_eq!(
+            watchpoints.take_hits(),
+            vec![WatchHit {
+                watchpoint: id,
+                pc: 4,
+                access: Access::Write,
+                target: WatchTarget::Register(2),
+                old: WatchValue::Integer(0),
+                new: WatchValue::Integer(14),
+            }]
+        );
+        assert_eq!(vm.run(), Ok(ExitReason::EndOfProgram));
+        assert!(watchpoints.hits().is_empty());
+    }
+
+    #[test]
+    fn test_register_read_watch() {
+        let watchpoints = Watchpoints::new();
+        let mut vm = watched_vm(&watchpoints);
+        watchpoints.add(WatchTarget::Register(1), WatchOn::Read, WatchAction::Pause);
+        vm.program = vec![0, 1, 0, 7, 1, 1, 0, 2];
+        assert_eq!(vm.run(), Ok(ExitReason::Paused));
+        let hits = watchpoints.hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].pc, 4);
+        assert_eq!(hits[0].access, Access::Read);
+        assert_eq!(hits[0].new, WatchValue::Integer(7));
+    }
+
+    #[test]
+    fn test_heap_range_callback() {
+        let watchpoints = Watchpoints::new();
+        let mut vm = watched_vm(&watchpoints);
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        watchpoints.add(
+            WatchTarget::Heap { start: 6, len: 2 },
+            WatchOn::ReadOrWrite,
+            WatchAction::Callback(Box::new(move |hit| log.borrow_mut().push(hit.clone()))),
+        );
+        vm.registers[0] = 8;
+        vm.registers[2] = 0x01020304;
+        // ALOC $0 $1; STOREB $2 $1 $3; STOREW $2 $1 $3; LOADH $4 $1 $5
+        vm.registers[5] = 2;
+        vm.program = vec![18, 0, 1, 0, 28, 2, 1, 3, 30, 2, 1, 3, 26, 4, 1, 5];
+        assert_eq!(vm.run(), Ok(ExitReason::EndOfProgram));
+        let seen = seen.borrow();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[0].pc, 8);
+        assert_eq!(seen[0].target, WatchTarget::Heap { start: 4, len: 4 });
+        assert_eq!(seen[0].old, WatchValue::Bytes(vec![4, 0, 0, 0]));
+        assert_eq!(seen[0].new, WatchValue::Bytes(vec![1, 2, 3, 4]));
+        assert_eq!(seen[1].access, Access::Read);
+        assert_eq!(seen[1].new, WatchValue::Bytes(vec![3, 4]));
+        assert!(watchpoints.hits().is_empty());
+    }
+
+    #[test]
+    fn test_remove() {
+        let watchpoints = Watchpoints::new();
+        let id = watchpoints.add(WatchTarget::FloatRegister(0), WatchOn::Write, WatchAction::Pause);
+        assert_eq!(watchpoints.len(), 1);
+        assert!(watchpoints.remove(id));
+        assert!(!watchpoints.remove(id));
+        assert!(watchpoints.is_empty());
+    }
+}